@@ -0,0 +1,218 @@
+/*
+Console side of the protocols - lets us drive the hosts end-to-end without a switch
+*/
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
+
+use smol::{
+    fs::File,
+    future,
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    lock::RwLock,
+};
+use thiserror::Error;
+
+use crate::{
+    device::{
+        CHUNK_SIZE, SwitchCommError, UsbClient,
+        interface::SwitchInterface,
+        transport::{
+            SwitchTransport,
+            pipe::{PipeTransport, duplex},
+        },
+    },
+    listing::Listing,
+};
+
+mod sphaira;
+mod tinfoil;
+
+const PROBE_N: u64 = 0x4000; // roughly what installers read when parsing headers
+
+#[derive(Error, Debug)]
+pub enum EmulatorError {
+    #[error("io error")]
+    IoError(#[from] io::Error),
+    #[error("host error: {0:?}")]
+    Host(#[from] SwitchCommError),
+    #[error("host stopped responding")]
+    HostExited,
+    #[error("bad magic from host")]
+    BadMagic,
+    #[error("bad response: {0}")]
+    BadResponse(String),
+    #[error("data mismatch in {0:?} at offset {1}")]
+    Mismatch(PathBuf, u64),
+}
+
+#[derive(Debug, Default)]
+pub struct EmulatorReport {
+    pub titles: usize,
+    pub ranges: usize,
+    pub bytes: u64,
+}
+
+pub struct SwitchEmulator {
+    transport: PipeTransport,
+    listing: Arc<RwLock<Listing>>,
+    report: EmulatorReport,
+}
+
+/// Runs a host over an in-memory pipe and checks everything it sends back against the listing
+pub async fn emulate(
+    client: UsbClient,
+    listing: Arc<RwLock<Listing>>,
+) -> Result<EmulatorReport, EmulatorError> {
+    let (mut emulator, host_end) = SwitchEmulator::connect(listing);
+    // host has to work out who we are, same as with a real switch
+    let host = UsbClient::serve(None, host_end);
+
+    let console = async {
+        match client {
+            UsbClient::Tinfoil => emulator.run_tinfoil().await,
+            UsbClient::Sphaira => emulator.run_sphaira().await,
+        }
+    };
+    against(host, console).await?;
+
+    Ok(emulator.report)
+}
+
+/// tinfoil's host never returns, sphaira's returns once we send exit - either way, console decides when we're done
+/// (its end of the pipe goes with it, so a console still waiting on it finds out)
+async fn against(
+    host: impl Future<Output = Result<(), SwitchCommError>>,
+    console: impl Future<Output = Result<(), EmulatorError>>,
+) -> Result<(), EmulatorError> {
+    future::or(console, async {
+        host.await?;
+        future::pending().await
+    })
+    .await
+}
+
+/// ranges an installer would plausibly ask for - header, across a chunk boundary, tail, then the whole thing
+fn probe_ranges(size: u64) -> Vec<(u64, u64)> {
+    let mut ranges = vec![(0, size.min(PROBE_N))];
+    if size > CHUNK_SIZE + PROBE_N {
+        ranges.push((CHUNK_SIZE - PROBE_N, CHUNK_SIZE + PROBE_N));
+    }
+    if size > PROBE_N {
+        ranges.push((size - PROBE_N, size));
+        ranges.push((0, size));
+    }
+    ranges
+}
+
+impl SwitchEmulator {
+    /// a console on one end of a pipe, and the host's end of it
+    fn connect(listing: Arc<RwLock<Listing>>) -> (Self, SwitchInterface) {
+        let (host_end, console_end) = duplex();
+        let emulator = Self {
+            transport: console_end,
+            listing: listing.clone(),
+            report: Default::default(),
+        };
+        (emulator, SwitchInterface::new(host_end, listing))
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), EmulatorError> {
+        match self.transport.rx().read_exact(buf).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(EmulatorError::HostExited),
+            res => Ok(res?),
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), EmulatorError> {
+        let tx = self.transport.tx();
+        tx.write_all(buf).await?;
+        tx.flush().await?;
+        Ok(())
+    }
+
+    /// compares what the host sent with what's actually on disk
+    async fn verify_range(
+        &mut self,
+        path: &PathBuf,
+        start: u64,
+        received: &[u8],
+    ) -> Result<(), EmulatorError> {
+        let mut f = File::open(path).await?;
+        f.seek(SeekFrom::Start(start)).await?;
+
+        let mut expected = vec![0u8; received.len()];
+        f.read_exact(&mut expected).await?;
+
+        if let Some(i) = expected.iter().zip(received).position(|(a, b)| a != b) {
+            return Err(EmulatorError::Mismatch(path.clone(), start + i as u64));
+        }
+
+        self.report.ranges += 1;
+        self.report.bytes += received.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::Timer;
+
+    use crate::{device::DETECT_TIMEOUT, fixture::Library, scan::ScanRules};
+
+    pub(super) fn listing(lib: &Library) -> Arc<RwLock<Listing>> {
+        let mut listing = Listing::new(ScanRules::default());
+        listing.add(Some("Lib"), lib.path()).unwrap();
+        Arc::new(RwLock::new(listing))
+    }
+
+    #[test]
+    fn both_protocols() {
+        // one past a chunk boundary, one past the cached head, one tiny, and a second copy of a title
+        let lib = Library::new("emulate");
+        lib.write(
+            "Big [0100000000010000][v0].nsp",
+            (CHUNK_SIZE + 0x10000) as usize,
+        );
+        lib.write("Mid [0100000000020000][v65536].nsp", 0x20000);
+        lib.write("Tiny [0100000000030000][v0].nsp", 100);
+        lib.write("Backup/Mid [0100000000020000][v65536].nsp", 0x30000);
+        let listing = listing(&lib);
+
+        let (tinfoil, sphaira) = smol::block_on(async {
+            let tinfoil = emulate(UsbClient::Tinfoil, listing.clone()).await?;
+            let sphaira = emulate(UsbClient::Sphaira, listing.clone()).await?;
+            Ok::<_, EmulatorError>((tinfoil, sphaira))
+        })
+        .unwrap();
+
+        let listing = listing.read_blocking();
+        assert_eq!(tinfoil.titles, listing.id_map().len());
+        // sphaira asks for every file by name, copies of a title included
+        assert_eq!(sphaira.titles, listing.file_map().len());
+        assert!(tinfoil.ranges > tinfoil.titles && sphaira.ranges > sphaira.titles);
+    }
+
+    #[test]
+    fn tinfoil_serves_ranges() {
+        // downloads are `id/start/end` - a range further in has to come from there, not from the start of the file
+        let lib = Library::new("tinfoil-ranges");
+        lib.write("Zelda [0100000000010000][v0].nsp", 3 * PROBE_N as usize);
+
+        smol::block_on(emulate(UsbClient::Tinfoil, listing(&lib))).unwrap();
+    }
+
+    #[test]
+    fn late_tinfoil() {
+        // taken for sphaira at first - the list it was about to get has to go, or it'd read it as its reply
+        let lib = Library::new("late-tinfoil");
+        lib.write("Zelda [0100000000010000][v0].nsp", PROBE_N as usize);
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let console = async {
+            Timer::after(DETECT_TIMEOUT * 2).await;
+            emulator.run_tinfoil().await
+        };
+        smol::block_on(against(UsbClient::serve(None, host_end), console)).unwrap();
+        assert_eq!(emulator.report.titles, 1);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{collections::HashSet, mem};
+
+use bytemuck::{bytes_of, from_bytes};
+
+use crate::{
+    client::{EmulatorError, SwitchEmulator, probe_ranges},
+    device::hosts::sphaira::{
+        CmdType,
+        packet::{CMD_MAGIC, CmdPacket, FileRangePacket, LIST_MAGIC, ListPacketResponse},
+    },
+};
+
+impl SwitchEmulator {
+    async fn read_list(&mut self) -> Result<Vec<String>, EmulatorError> {
+        let mut buf = [0u8; mem::size_of::<ListPacketResponse>()];
+        self.read_exact(&mut buf).await?;
+
+        let list_header: &ListPacketResponse = from_bytes(&buf);
+        if list_header.packet_type != LIST_MAGIC {
+            return Err(EmulatorError::BadMagic);
+        }
+
+        let mut list = vec![0u8; list_header.len as usize];
+        self.read_exact(&mut list).await?;
+
+        let list = String::from_utf8(list)
+            .map_err(|_| EmulatorError::BadResponse("non utf-8 file list".to_string()))?;
+        Ok(list.lines().map(|s| s.to_string()).collect())
+    }
+
+    async fn send_cmd(&mut self, cmd_type: CmdType, data_size: u64) -> Result<(), EmulatorError> {
+        let cmd = CmdPacket {
+            magic: CMD_MAGIC,
+            cmd_id: cmd_type.into(),
+            data_size,
+            ..Default::default()
+        };
+        self.write_all(bytes_of(&cmd)).await
+    }
+
+    /// asks for a range and reads the reply header - the data's next, if it's a FileRange
+    async fn start_range(
+        &mut self,
+        name: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<CmdPacket, EmulatorError> {
+        let range = FileRangePacket {
+            range_size: end - start,
+            range_offset: start,
+            name_len: name.len() as u64,
+            ..Default::default()
+        };
+        self.send_file_range(&range, name.as_bytes()).await
+    }
+
+    /// whatever `range` says, `name` is what's sent after it
+    async fn send_file_range(
+        &mut self,
+        range: &FileRangePacket,
+        name: &[u8],
+    ) -> Result<CmdPacket, EmulatorError> {
+        self.send_cmd(
+            CmdType::FileRange,
+            (mem::size_of::<FileRangePacket>() + name.len()) as u64,
+        )
+        .await?;
+        self.write_all(bytes_of(range)).await?;
+        self.write_all(name).await?;
+
+        let mut buf = [0u8; mem::size_of::<CmdPacket>()];
+        self.read_exact(&mut buf).await?;
+        let reply: CmdPacket = *from_bytes(&buf);
+        if reply.magic != CMD_MAGIC {
+            return Err(EmulatorError::BadMagic);
+        }
+        Ok(reply)
+    }
+
+    async fn request_range(
+        &mut self,
+        name: &str,
+        start: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, EmulatorError> {
+        let cmd_id = self.start_range(name, start, end).await?.cmd_id;
+        if cmd_id != CmdType::FileRange.into() {
+            return Err(EmulatorError::BadResponse(format!(
+                "{name:?} refused with cmd {cmd_id}"
+            )));
+        }
+
+        let mut data = vec![0u8; (end - start) as usize];
+        self.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    pub async fn run_sphaira(&mut self) -> Result<(), EmulatorError> {
+        let names = self.read_list().await?;
+
+        let listing = self.listing.read().await;
+        let expected = listing.file_map().keys().collect::<HashSet<_>>();
+        if names.iter().collect::<HashSet<_>>() != expected {
+            return Err(EmulatorError::BadResponse(format!(
+                "list was {names:?}, expected {expected:?}"
+            )));
+        }
+
+        let games = names
+            .into_iter()
+            .filter_map(|name| {
+                let (path, size, _) = listing.resolve(&name)?;
+                Some((path.to_path_buf(), size, name))
+            })
+            .collect::<Vec<_>>();
+        drop(listing);
+
+        for (path, size, name) in games {
+            for (start, end) in probe_ranges(size) {
+                let data = self.request_range(&name, start, end).await?;
+                self.verify_range(&path, start, &data).await?;
+            }
+            self.report.titles += 1;
+        }
+
+        self.send_cmd(CmdType::Exit, 0).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        client::{PROBE_N, against, tests::listing},
+        device::{SwitchCommError, UsbClient, hosts::sphaira::packet::MAX_NAME_N},
+        fixture::Library,
+        history,
+    };
+
+    #[test]
+    fn cancelled_range_is_dropped() {
+        // the console asks for something else part way through a range - what's left of the first never reaches it
+        let lib = Library::new("sphaira-cancel");
+        let path = lib.write("Zelda [0100000000010000][v0].nsp", 0x400000);
+        let name = "Lib/Zelda [0100000000010000][v0].nsp";
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let console = async {
+            emulator.read_list().await?;
+            emulator.start_range(name, 0, 0x400000).await?;
+            let mut head = vec![0u8; PROBE_N as usize];
+            emulator.read_exact(&mut head).await?;
+            emulator.verify_range(&path, 0, &head).await?;
+
+            // the next reply has to come straight after, with nothing of the first range in front of it
+            let tail = emulator.request_range(name, 0x300000, 0x300100).await?;
+            emulator.verify_range(&path, 0x300000, &tail).await?;
+            emulator.send_cmd(CmdType::Exit, 0).await
+        };
+        let host = UsbClient::serve(Some(UsbClient::Sphaira), host_end);
+        smol::block_on(against(host, console)).unwrap();
+    }
+
+    #[test]
+    fn refusals_are_audited() {
+        // a real file beside the library, then the same through the label - neither's listed, so neither's served
+        // (a title no other test asks for - the audit file's global while it's set)
+        let lib = Library::new("sphaira-refuse");
+        let path = lib.write("Mario [0100000000020000][v0].nsp", 0x1000);
+        let secret = lib.write("secret.txt", 0x10);
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+        history::set_history(Some(lib.path().join("history.jsonl")));
+
+        let requests = [
+            (secret.to_str().unwrap().to_string(), 0, 0x10),
+            ("Lib/../secret.txt".to_string(), 0, 0x10),
+            (
+                "Lib/Mario [0100000000020000][v0].nsp".to_string(),
+                0x800,
+                0x1800,
+            ),
+        ];
+        let console = async {
+            emulator.read_list().await?;
+            for (name, start, end) in &requests {
+                // exit's sphaira's only refusal
+                let cmd_id = emulator.start_range(name, *start, *end).await?.cmd_id;
+                assert_eq!(cmd_id, u32::from(CmdType::Exit), "{name}");
+            }
+            // and the session carries on after them
+            let data = emulator.request_range(&requests[2].0, 0, 0x1000).await?;
+            emulator.verify_range(&path, 0, &data).await?;
+            emulator.send_cmd(CmdType::Exit, 0).await
+        };
+        let host = UsbClient::serve(Some(UsbClient::Sphaira), host_end);
+        smol::block_on(against(host, console)).unwrap();
+        history::set_history(None);
+
+        let audit = fs::read_to_string(lib.path().join("audit.jsonl")).unwrap();
+        for (name, _, _) in &requests {
+            assert_eq!(
+                audit.lines().filter(|l| l.contains(name.as_str())).count(),
+                1,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_file_ranges_get_an_exit() {
+        let lib = Library::new("sphaira-bad-range");
+        let path = lib.write("Zelda [0100000000010000][v0].nsp", 0x1000);
+        let name = "Lib/Zelda [0100000000010000][v0].nsp";
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let range = |start, len, name_len| FileRangePacket {
+            range_size: len,
+            range_offset: start,
+            name_len,
+            ..Default::default()
+        };
+        let requests = [
+            // NameTooLong, SizeMismatch, RangeOutOfBounds
+            (range(0, 0x10, MAX_NAME_N + 1), name),
+            (range(0, 0x10, name.len() as u64 + 1), name),
+            (range(u64::MAX, 2, name.len() as u64), name),
+        ];
+        let console = async {
+            emulator.read_list().await?;
+            for (range, name) in &requests {
+                let cmd_id = emulator
+                    .send_file_range(range, name.as_bytes())
+                    .await?
+                    .cmd_id;
+                assert_eq!(cmd_id, u32::from(CmdType::Exit));
+            }
+            let data = emulator.request_range(name, 0, 0x1000).await?;
+            emulator.verify_range(&path, 0, &data).await?;
+            emulator.send_cmd(CmdType::Exit, 0).await
+        };
+        let host = UsbClient::serve(Some(UsbClient::Sphaira), host_end);
+        smol::block_on(against(host, console)).unwrap();
+    }
+
+    #[test]
+    fn oversized_payload_ends_the_session() {
+        // can't be skipped over, so there's no telling where the next command starts
+        let lib = Library::new("sphaira-oversized");
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let console = async {
+            emulator.read_list().await?;
+            emulator.send_cmd(CmdType::FileRange, 1 << 40).await?;
+            emulator.read_exact(&mut [0u8; 1]).await
+        };
+        let host = UsbClient::serve(Some(UsbClient::Sphaira), host_end);
+        let e = smol::block_on(against(host, console)).unwrap_err();
+        assert!(matches!(
+            e,
+            EmulatorError::Host(SwitchCommError::PayloadTooLarge(..))
+        ));
+    }
+}
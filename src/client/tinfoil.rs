@@ -0,0 +1,192 @@
+use std::{collections::HashSet, mem};
+
+use bytemuck::bytes_of;
+use miniserde::json::{self, Number, Value};
+
+use crate::{
+    client::{EmulatorError, SwitchEmulator, probe_ranges},
+    device::hosts::tinfoil::{DEFAULT_CMD, packet::CommandPacket},
+};
+
+impl SwitchEmulator {
+    async fn send_query(&mut self, query: &str) -> Result<(), EmulatorError> {
+        self.write_all(bytes_of(&CommandPacket::new(
+            DEFAULT_CMD,
+            query.len() as u64,
+        )))
+        .await?;
+        self.write_all(query.as_bytes()).await
+    }
+
+    /// returns the size the host put in the header
+    async fn read_header(&mut self) -> Result<u64, EmulatorError> {
+        let mut buf = [0u8; mem::size_of::<CommandPacket>()];
+        self.read_exact(&mut buf).await?;
+        let packet = CommandPacket::from_raw(&buf).ok_or(EmulatorError::BadMagic)?;
+        Ok(packet.size)
+    }
+
+    async fn read_json(&mut self) -> Result<Value, EmulatorError> {
+        let size = self.read_header().await?;
+        let mut payload = vec![0u8; size as usize];
+        self.read_exact(&mut payload).await?;
+
+        let payload = String::from_utf8(payload)
+            .map_err(|_| EmulatorError::BadResponse("non utf-8 response".to_string()))?;
+        json::from_str(&payload).map_err(|_| EmulatorError::BadResponse(payload))
+    }
+
+    async fn check_search(&mut self, ids: &HashSet<String>) -> Result<(), EmulatorError> {
+        self.send_query("/api/search").await?;
+
+        let Value::Array(games) = self.read_json().await? else {
+            return Err(EmulatorError::BadResponse(
+                "search isn't a list".to_string(),
+            ));
+        };
+
+        let listed = games
+            .iter()
+            .filter_map(|g| match g {
+                Value::Object(o) => match o.get("id") {
+                    Some(Value::String(id)) => Some(id.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        if &listed != ids {
+            return Err(EmulatorError::BadResponse(format!(
+                "search listed {listed:?}, expected {ids:?}"
+            )));
+        }
+        Ok(())
+    }
+
+    async fn check_info(&mut self, id: &str, size: u64) -> Result<(), EmulatorError> {
+        self.send_query(&format!("/api/info/{id}")).await?;
+
+        let Value::Object(info) = self.read_json().await? else {
+            return Err(EmulatorError::BadResponse(format!(
+                "info for {id} isn't an object"
+            )));
+        };
+
+        match (info.get("id"), info.get("size")) {
+            (Some(Value::String(i)), Some(Value::Number(Number::U64(s))))
+                if i == id && *s == size =>
+            {
+                Ok(())
+            }
+            _ => Err(EmulatorError::BadResponse(format!("bad info for {id}"))),
+        }
+    }
+
+    /// host sends a header with the size of every chunk, until the range is done
+    async fn read_download(&mut self, mut remaining: u64) -> Result<Vec<u8>, EmulatorError> {
+        let mut data = Vec::with_capacity(remaining as usize);
+        loop {
+            let n = self.read_header().await?;
+            if n > remaining {
+                return Err(EmulatorError::BadResponse(format!(
+                    "{n} byte chunk with {remaining} bytes left"
+                )));
+            }
+
+            let start = data.len();
+            data.resize(start + n as usize, 0);
+            self.read_exact(&mut data[start..]).await?;
+            remaining -= n;
+
+            if remaining == 0 {
+                return Ok(data);
+            }
+        }
+    }
+
+    pub async fn run_tinfoil(&mut self) -> Result<(), EmulatorError> {
+        let games = self
+            .listing
+            .read()
+            .await
+            .id_map()
+            .iter()
+            .map(|(id, g)| (id.clone(), g.path().clone(), g.size()))
+            .collect::<Vec<_>>();
+
+        self.check_search(&games.iter().map(|(id, ..)| id.clone()).collect())
+            .await?;
+
+        for (id, path, size) in games {
+            self.check_info(&id, size).await?;
+
+            for (start, end) in probe_ranges(size) {
+                self.send_query(&format!("/api/download/{id}/{start}/{end}"))
+                    .await?;
+                let data = self.read_download(end - start).await?;
+                self.verify_range(&path, start, &data).await?;
+            }
+            self.report.titles += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::{against, tests::listing},
+        device::{SwitchCommError, UsbClient, codec::Header},
+        fixture::Library,
+    };
+
+    #[test]
+    fn download_past_the_end_is_refused() {
+        let lib = Library::new("tinfoil-past-end");
+        let path = lib.write("Zelda [0100000000010000][v0].nsp", 0x1000);
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let console = async {
+            emulator
+                .send_query("/api/download/0100000000010000/0/4097")
+                .await?;
+            let Value::Object(status) = emulator.read_json().await? else {
+                return Err(EmulatorError::BadResponse(
+                    "status isn't an object".to_string(),
+                ));
+            };
+            assert!(matches!(status.get("success"), Some(Value::Bool(false))));
+
+            // and the session carries on
+            emulator
+                .send_query("/api/download/0100000000010000/0/4096")
+                .await?;
+            let data = emulator.read_download(0x1000).await?;
+            emulator.verify_range(&path, 0, &data).await
+        };
+        let host = UsbClient::serve(Some(UsbClient::Tinfoil), host_end);
+        smol::block_on(against(host, console)).unwrap();
+    }
+
+    #[test]
+    fn oversized_query_ends_the_session() {
+        let lib = Library::new("tinfoil-oversized");
+        let (mut emulator, host_end) = SwitchEmulator::connect(listing(&lib));
+
+        let console = async {
+            let size = CommandPacket::MAX_PAYLOAD_N + 1;
+            emulator
+                .write_all(bytes_of(&CommandPacket::new(DEFAULT_CMD, size)))
+                .await?;
+            emulator.read_header().await.map(drop)
+        };
+        let host = UsbClient::serve(Some(UsbClient::Tinfoil), host_end);
+        let e = smol::block_on(against(host, console)).unwrap_err();
+        assert!(matches!(
+            e,
+            EmulatorError::Host(SwitchCommError::PayloadTooLarge(..))
+        ));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{fs, io, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::device::{
+    UsbClient,
+    filter::{DeviceFilter, DeviceSelector},
+    throttle::parse_rate,
+};
+use crate::history::{Query, parse_date, parse_end_date};
+use crate::scan::ScanRules;
+
+pub const USAGE: &str = "frhop [emulate] [-s|-t] [--id vid:pid]... [--serial serial | --port bus-port.port] [--read-ahead n] [--limit rate] [--device-limit rate] [--history-file file] [--depth n] [--include glob]... [--exclude glob]... [--config file] {list of [label=]directories or nsps}
+       frhop history [--console serial] [--title id|name] [--since yyyy-mm-dd] [--until yyyy-mm-dd] [--outcome completed|abandoned] [--history-file file]";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("missing value for --{0}")]
+    MissingValue(String),
+    #[error("unknown option: {0}")]
+    UnknownOption(String),
+    #[error("bad value for {0}: {1}")]
+    BadValue(String, String),
+    #[error("failed to read config file: {0}")]
+    IoError(#[from] io::Error),
+}
+
+#[derive(Default)]
+pub struct Config {
+    pub emulate: bool, // run against a fake switch instead of waiting on usb
+    pub history: bool, // print the install history and exit
+    pub client: Option<UsbClient>, // None -> detect per device
+    pub filter: DeviceFilter,
+    pub read_ahead: Option<usize>, // chunks read from disk ahead of usb, None -> writer's default
+    pub limit: Option<u64>,        // bytes/s across every session
+    pub device_limit: Option<u64>, // bytes/s per session
+    pub history_file: Option<PathBuf>, // None -> history's default
+    pub query: Query,
+    pub scan: ScanRules,
+    pub paths: Vec<String>,
+}
+
+impl Config {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+
+        let mut args = args.peekable();
+        config.emulate = args.next_if(|a| a == "emulate").is_some();
+        config.history = !config.emulate && args.next_if(|a| a == "history").is_some();
+
+        while let Some(arg) = args.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                let (key, value) = match key.split_once('=') {
+                    Some((k, v)) => (k.to_string(), v.to_string()),
+                    None => (
+                        key.to_string(),
+                        args.next()
+                            .ok_or(ConfigError::MissingValue(key.to_string()))?,
+                    ),
+                };
+                config.set(&key, &value)?;
+            } else if let Some(("", t)) = arg.split_once("-")
+                && let Ok(c) = UsbClient::try_from(t)
+            {
+                config.client = Some(c);
+            } else {
+                config.paths.push(arg);
+            }
+        }
+        Ok(config)
+    }
+
+    /// shared by cli flags and config files - keys are the long flag names
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let bad_value = || ConfigError::BadValue(key.to_string(), value.to_string());
+        match key {
+            "id" => self.filter.allow(value.parse().map_err(|_| bad_value())?),
+            "serial" => self
+                .filter
+                .select(DeviceSelector::Serial(value.to_string())),
+            "port" => self.filter.select(DeviceSelector::Port(value.to_string())),
+            "client" => self.client = Some(UsbClient::try_from(value).map_err(|_| bad_value())?),
+            "read-ahead" => self.read_ahead = Some(value.parse().map_err(|_| bad_value())?),
+            "limit" => self.limit = Some(parse_rate(value).ok_or_else(bad_value)?),
+            "device-limit" => self.device_limit = Some(parse_rate(value).ok_or_else(bad_value)?),
+            "history-file" => self.history_file = Some(PathBuf::from(value)),
+            "console" => self.query.console = Some(value.to_string()),
+            "title" => self.query.title = Some(value.to_string()),
+            "since" => self.query.since = Some(parse_date(value).ok_or_else(bad_value)?),
+            "until" => self.query.until = Some(parse_end_date(value).ok_or_else(bad_value)?),
+            "outcome" => match value {
+                "completed" | "abandoned" => self.query.outcome = Some(value.to_string()),
+                _ => return Err(bad_value()),
+            },
+            "depth" => self.scan.max_depth = Some(value.parse().map_err(|_| bad_value())?),
+            "include" => self.scan.include.push(value.to_string()),
+            "exclude" => self.scan.exclude.push(value.to_string()),
+            "path" => self.paths.push(value.to_string()),
+            "config" => self.load_file(value)?,
+            _ => return Err(ConfigError::UnknownOption(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// `key = value` per line, # for comments
+    fn load_file(&mut self, path: &str) -> Result<(), ConfigError> {
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(ConfigError::BadValue(path.to_string(), line.to_string()))?;
+            self.set(key.trim(), value.trim())?;
+        }
+        Ok(())
+    }
+}
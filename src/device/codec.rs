@@ -0,0 +1,73 @@
+use bytemuck::{Pod, bytes_of, bytes_of_mut};
+
+use crate::device::{SwitchCommError, interface::SwitchInterface};
+
+/*
+Framing shared by the hosts - a fixed size header (magic + payload length) with the payload straight after
+Reads are all or nothing, so a short usb read can't leave half a command behind
+Payload lengths come from the console, so they're capped per header before anything's allocated
+Headers go out as their own transfer, and messages end in a short packet - a payload that's an exact multiple of the
+max packet size gets a zero length one, otherwise a console reading with a bigger buffer would sit waiting for more
+*/
+
+/// Fixed size header in front of a payload
+pub trait Header: Pod {
+    /// anything longer is a corrupt (or hostile) header
+    const MAX_PAYLOAD_N: u64;
+
+    /// magic matches
+    fn is_valid(&self) -> bool;
+    fn payload_len(&self) -> u64;
+}
+
+impl SwitchInterface {
+    /// waits as long as it takes for the next one, see read_command
+    pub async fn read_header<H: Header>(&mut self) -> Result<H, SwitchCommError> {
+        let mut header = H::zeroed();
+        self.read_command(bytes_of_mut(&mut header)).await?;
+        if !header.is_valid() {
+            self.unread(bytes_of(&header)); // left for whoever can make sense of it, see SphairaInterface
+            return Err(SwitchCommError::BadMagic);
+        }
+        Ok(header)
+    }
+
+    /// everything the header says follows it
+    pub async fn read_payload<H: Header>(
+        &mut self,
+        header: &H,
+    ) -> Result<Vec<u8>, SwitchCommError> {
+        let len = header.payload_len();
+        if len > H::MAX_PAYLOAD_N {
+            return Err(SwitchCommError::PayloadTooLarge(len, H::MAX_PAYLOAD_N));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        self.read_exact(&mut payload).await?;
+        Ok(payload)
+    }
+
+    /// header as a transfer of its own - the console reads it separately from whatever follows
+    pub async fn write_header<H: Header>(&mut self, header: &H) -> Result<(), SwitchCommError> {
+        self.write_all(bytes_of(header)).await?;
+        self.submit();
+        Ok(())
+    }
+
+    /// a whole message, waited on until it's gone out
+    pub async fn write_frame<H: Header>(
+        &mut self,
+        header: &H,
+        payload: &[u8],
+    ) -> Result<(), SwitchCommError> {
+        self.write_header(header).await?;
+        self.write_all(payload).await?;
+        self.end_message().await
+    }
+
+    /// short packet (zero length if need be) so the console's read completes, then waits on it
+    pub async fn end_message(&mut self) -> Result<(), SwitchCommError> {
+        self.submit_end();
+        self.flush().await
+    }
+}
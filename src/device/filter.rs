@@ -0,0 +1,113 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{LazyLock, RwLock},
+};
+
+use nusb::DeviceInfo;
+
+use crate::device::transport::DeviceIdent;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HostId {
+    vendor: u16,
+    prod: u16,
+}
+
+const DEFAULT_DEVICES: [HostId; 2] = [
+    HostId {
+        vendor: 0x16C0,
+        prod: 0x27E2,
+    },
+    HostId {
+        vendor: 0x057E,
+        prod: 0x3000,
+    },
+];
+
+/// Pins an instance to one switch, so several instances can share a machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    Serial(String),
+    Port(String), // bus-port.port... - see DeviceIdent::port_path
+}
+
+/// Which usb devices we'll try to talk to
+#[derive(Debug, Clone)]
+pub struct DeviceFilter {
+    ids: Vec<HostId>,
+    selector: Option<DeviceSelector>,
+}
+
+// read on every hotplug event, written once config is loaded
+static FILTER: LazyLock<RwLock<DeviceFilter>> = LazyLock::new(|| RwLock::new(Default::default()));
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self {
+            ids: DEFAULT_DEVICES.to_vec(),
+            selector: None,
+        }
+    }
+}
+
+impl FromStr for HostId {
+    type Err = ();
+    /// `vid:pid` in hex, e.g. `057e:3000`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (vendor, prod) = s.split_once(':').ok_or(())?;
+        Ok(Self {
+            vendor: u16::from_str_radix(vendor.trim_start_matches("0x"), 16).map_err(|_| ())?,
+            prod: u16::from_str_radix(prod.trim_start_matches("0x"), 16).map_err(|_| ())?,
+        })
+    }
+}
+
+impl Display for HostId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.prod)
+    }
+}
+
+impl DeviceSelector {
+    fn matches(&self, ident: &DeviceIdent) -> bool {
+        match self {
+            Self::Serial(s) => ident.serial.as_ref() == Some(s),
+            Self::Port(p) => &ident.port_path() == p,
+        }
+    }
+}
+
+impl DeviceFilter {
+    pub fn allow(&mut self, id: HostId) {
+        if !self.ids.contains(&id) {
+            self.ids.push(id);
+        }
+    }
+
+    pub fn select(&mut self, selector: DeviceSelector) {
+        self.selector.replace(selector);
+    }
+
+    pub fn matches(&self, d_info: &DeviceInfo) -> bool {
+        let allowed = self
+            .ids
+            .iter()
+            .any(|d| d.prod == d_info.product_id() && d.vendor == d_info.vendor_id());
+
+        allowed
+            && self
+                .selector
+                .as_ref()
+                .is_none_or(|s| s.matches(&DeviceIdent::from(d_info)))
+    }
+}
+
+/// swap in a whole new filter - e.g. once config is parsed
+pub fn set_filter(filter: DeviceFilter) {
+    *FILTER.write().unwrap() = filter;
+}
+
+pub fn device_allowed(d_info: &DeviceInfo) -> bool {
+    FILTER.read().unwrap().matches(d_info)
+}
@@ -1,21 +1,25 @@
-use std::io;
+use std::{io, mem};
 
 use bytemuck::bytes_of;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use smol::io::AsyncWriteExt;
 use thiserror::Error;
 
 use crate::device::{
-    SwitchCommError, SwitchHost, SwitchHostImpl, hosts::sphaira::packet::ListPacketResponse,
-    interface::SwitchInterface, writer::SwitchHostWriterExt,
+    SwitchCommError, SwitchHost, SwitchHostImpl, UsbClient,
+    hosts::{
+        sphaira::packet::{ListPacketResponse, MAX_NAME_N},
+        tinfoil::{TinfoilInterface, packet::CommandPacket},
+    },
+    interface::SwitchInterface,
+    writer::SwitchHostWriterExt,
 };
 
-mod packet;
+pub mod packet;
 mod server;
 
 pub struct SphairaInterface {
     inner: SwitchInterface,
-    current_f: Option<String>,
+    detected: bool, // guessed from a quiet switch, not forced - a tinfoil command hands over to tinfoil
 }
 
 #[derive(Error, Debug)]
@@ -24,6 +28,16 @@ pub enum SphairaError {
     SwitchComm(#[from] SwitchCommError),
     #[error("non utf-8 char in name")]
     BadFileName,
+    #[error("file range cmd too short")]
+    BadFileRange,
+    #[error("{0} byte file name, at most {MAX_NAME_N} allowed")]
+    NameTooLong(u64),
+    #[error("cmd says {0} bytes follow, file range + name is {1}")]
+    SizeMismatch(u64, u64),
+    #[error("range {0}+{1} is past the end of the file ({2} bytes)")]
+    RangeOutOfBounds(u64, u64, u64),
+    #[error("not in the listing: {0:?}")]
+    NotListed(String),
     #[error("io erro")]
     IoError(#[from] io::Error),
 }
@@ -50,25 +64,49 @@ impl SwitchHost for SphairaInterface {
         let total_len = file_id_map.iter().map(|s| s.len()).sum::<usize>();
         let list_header = ListPacketResponse::new(total_len as u32);
 
-        let tx = self.get_interface_mut().get_tx();
-
-        println!("writing list header");
-        tx.write(bytes_of(&list_header)).await?;
-        tx.flush().await?;
-
-        println!("sending list");
-        for f in file_id_map {
-            tx.write(f.as_bytes()).await?;
+        let interface = self.get_interface_mut();
+
+        interface.log(format_args!("sending list"));
+        // watched like file data - a tinfoil slower than DETECT_TIMEOUT speaks up instead of reading it, and mustn't
+        // find it waiting once it's been handed over
+        let sent = async {
+            interface.write_transfer(bytes_of(&list_header)).await?;
+            interface.submit();
+            interface
+                .write_transfer(file_id_map.concat().as_bytes())
+                .await?;
+            interface.submit_end();
+            interface.flush_transfer().await
+        };
+        match sent.await {
+            Err(SwitchCommError::Cancelled) => {
+                interface.log(format_args!("list dropped, the switch spoke first"))
+            }
+            res => res?,
         }
-        tx.flush().await?;
 
         // start main loop
         loop {
             let cmd_type = match self.poll_command().await {
                 Ok(p) => p,
+                Err(SphairaError::SwitchComm(SwitchCommError::Cancelled)) => {
+                    self.get_interface().log(format_args!("download cancelled"));
+                    continue;
+                }
+                // tinfoil can take longer than DETECT_TIMEOUT to say anything - its command's been put back for it
+                Err(SphairaError::SwitchComm(SwitchCommError::BadMagic))
+                    if self.tinfoil_waiting() =>
+                {
+                    self.inner
+                        .log(format_args!("that's a tinfoil command, switching over"));
+                    self.inner.get_stats().set_protocol(UsbClient::Tinfoil);
+                    return TinfoilInterface::from(self.inner)
+                        .start_talkin_buddy()
+                        .await;
+                }
                 Err(SphairaError::SwitchComm(s_e)) => return Err(s_e),
                 Err(e) => {
-                    eprintln!("{e:?}");
+                    self.get_interface().log(format_args!("{e:?}"));
                     continue;
                 }
             };
@@ -80,11 +118,30 @@ impl SwitchHost for SphairaInterface {
     }
 }
 
+impl SphairaInterface {
+    /// auto-detected rather than asked for, so it might really be a slow tinfoil
+    pub fn detected(inner: SwitchInterface) -> Self {
+        Self {
+            inner,
+            detected: true,
+        }
+    }
+
+    fn tinfoil_waiting(&self) -> bool {
+        let peeked = self.inner.peek();
+        self.detected
+            && peeked
+                .get(..mem::size_of::<CommandPacket>())
+                .and_then(CommandPacket::from_raw)
+                .is_some()
+    }
+}
+
 impl From<SwitchInterface> for SphairaInterface {
     fn from(inner: SwitchInterface) -> Self {
         Self {
             inner,
-            current_f: None,
+            detected: false,
         }
     }
 }
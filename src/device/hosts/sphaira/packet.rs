@@ -1,15 +1,20 @@
+use std::mem;
+
 use bytemuck::{Pod, Zeroable};
 
+use crate::device::codec::Header;
+
 type PacketType = [u8; 4];
 
-const LIST_MAGIC: [u8; 4] = *b"TUL0";
+pub const LIST_MAGIC: [u8; 4] = *b"TUL0";
 pub const CMD_MAGIC: [u8; 4] = *b"TUC0";
+pub const MAX_NAME_N: u64 = 0x1000; // names are paths we listed, PATH_MAX is 4k
 
 #[repr(C, packed)]
 #[derive(Default, Pod, Clone, Copy, Zeroable)]
 pub struct ListPacketResponse {
-    packet_type: PacketType,
-    len: u32,
+    pub packet_type: PacketType,
+    pub len: u32,
     _padding: [u8; 8],
 }
 
@@ -30,7 +35,7 @@ pub struct FileRangePacket {
     pub range_size: u64,
     pub range_offset: u64,
     pub name_len: u64,
-    pub _padding0: [u8; 8]
+    pub _padding0: [u8; 8],
 }
 
 impl ListPacketResponse {
@@ -46,9 +51,34 @@ impl ListPacketResponse {
 impl CmdPacket {
     pub fn new(cmd_id: u32, data_size: u64) -> Self {
         Self {
+            magic: CMD_MAGIC, // sphaira doesn't seem to check but usb_install_pc.py always sends it
             cmd_id,
             data_size,
             ..Default::default()
         }
     }
 }
+
+impl Header for ListPacketResponse {
+    const MAX_PAYLOAD_N: u64 = u32::MAX as u64; // only ever sent, len is a u32 anyway
+
+    fn is_valid(&self) -> bool {
+        self.packet_type == LIST_MAGIC
+    }
+
+    fn payload_len(&self) -> u64 {
+        self.len as u64
+    }
+}
+
+impl Header for CmdPacket {
+    const MAX_PAYLOAD_N: u64 = mem::size_of::<FileRangePacket>() as u64 + MAX_NAME_N; // file range is the only cmd with a payload
+
+    fn is_valid(&self) -> bool {
+        self.magic == CMD_MAGIC
+    }
+
+    fn payload_len(&self) -> u64 {
+        self.data_size
+    }
+}
@@ -1,88 +1,126 @@
-use std::{io::SeekFrom, mem};
-
-use bytemuck::{bytes_of, from_bytes};
-use smol::{
-    fs,
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-};
-
-use crate::device::{
-    SwitchCommError, SwitchHostImpl,
-    hosts::sphaira::{
-        CmdType, SphairaError, SphairaInterface,
-        packet::{CMD_MAGIC, CmdPacket, FileRangePacket},
+use std::mem;
+
+use bytemuck::from_bytes;
+
+use crate::{
+    device::{
+        SwitchCommError, SwitchHostImpl,
+        hosts::sphaira::{
+            CmdType, SphairaError, SphairaInterface,
+            packet::{CmdPacket, FileRangePacket, MAX_NAME_N},
+        },
+        progress::Title,
+        shutdown, storage,
+        writer::SwitchHostWriterExt,
     },
-    writer::{ChunkStatus, SwitchHostWriterExt},
+    history::{self, Rejection},
 };
 
 impl SphairaInterface {
     pub async fn poll_command(&mut self) -> Result<CmdType, SphairaError> {
-        let mut buf = [0u8; mem::size_of::<CmdPacket>()];
-        self.get_interface_mut()
-            .get_rx()
-            .read_exact(&mut buf)
-            .await
-            .map_err(SwitchCommError::from)?;
-
-        let cmd_packet: &CmdPacket = from_bytes(&buf);
-
-        if cmd_packet.magic != CMD_MAGIC {
-            return Err(SwitchCommError::BadMagic)?;
-        }
+        let interface = self.get_interface_mut();
+        let header = interface.read_header::<CmdPacket>().await?;
+        let payload = interface.read_payload(&header).await?;
 
-        let Some(cmd_type): Option<CmdType> = CmdType::try_from(cmd_packet.cmd_id).ok() else {
+        let Some(cmd_type): Option<CmdType> = CmdType::try_from(header.cmd_id).ok() else {
             return Err(SwitchCommError::UnknownCmd)?;
         };
 
         match cmd_type {
-            CmdType::FileRange => self.serve_file(cmd_packet.data_size).await?,
+            CmdType::FileRange => match self.serve_file(header.data_size, &payload).await {
+                // refused before the reply - sphaira sits waiting for one, and exit's the only one it'll take
+                Err(e @ SphairaError::SwitchComm(_)) => return Err(e),
+                Err(e) => {
+                    self.send_exit().await?;
+                    return Err(e);
+                }
+                Ok(()) => (),
+            },
             CmdType::Exit => (),
         }
 
         Ok(cmd_type)
     }
 
-    pub async fn serve_file(&mut self, datasize: u64) -> Result<(), SphairaError> {
-        let mut buf = [0u8; mem::size_of::<FileRangePacket>()];
-        let rx = self.get_interface_mut().get_rx();
-        rx.read_exact(&mut buf)
-            .await
-            .map_err(SwitchCommError::from)?;
+    /// `payload` is a FileRangePacket, then the name
+    pub async fn serve_file(&mut self, datasize: u64, payload: &[u8]) -> Result<(), SphairaError> {
+        let (range, name) = payload
+            .split_at_checked(mem::size_of::<FileRangePacket>())
+            .ok_or(SphairaError::BadFileRange)?;
+        let file_range_packet: &FileRangePacket = from_bytes(range);
 
-        let file_range_packet: &FileRangePacket = from_bytes(&buf);
-
-        let mut name = vec![0u8; file_range_packet.name_len as usize];
-        rx.read_exact(&mut name)
-            .await
-            .map_err(SwitchCommError::from)?;
-
-        let name = String::from_utf8(name).map_err(|_| SphairaError::BadFileName)?;
+        let name_len = file_range_packet.name_len;
+        if name_len > MAX_NAME_N {
+            return Err(SphairaError::NameTooLong(name_len));
+        }
+        if name_len != name.len() as u64 {
+            return Err(SphairaError::SizeMismatch(
+                datasize,
+                mem::size_of::<FileRangePacket>() as u64 + name_len,
+            ));
+        }
+        let name = String::from_utf8(name.to_vec()).map_err(|_| SphairaError::BadFileName)?;
 
-        let mut f = fs::File::open(&name).await?;
-        f.seek(SeekFrom::Start(file_range_packet.range_offset))
-            .await?;
+        let (start, len) = (file_range_packet.range_offset, file_range_packet.range_size);
+        let Some(end) = start.checked_add(len) else {
+            self.reject(&name, "range past the end of the file");
+            return Err(SphairaError::RangeOutOfBounds(start, len, u64::MAX));
+        };
 
-        let mut f = f.take(file_range_packet.range_size);
+        if shutdown::is_stopping() {
+            self.send_exit().await?;
+            return Err(SwitchCommError::ShuttingDown)?;
+        }
 
-        if Some(&name) != self.current_f.as_ref() {
-            println!("\"{name}\" requested");
-            self.current_f.replace(name);
+        // only ever what we listed - the name is a path, and anything else on disk is none of its business
+        let listing = self.get_interface().get_listing().await;
+        // the name's own file - another with the same title id may be what the game was read from
+        let Some((path, size, game)) = listing.resolve(&name) else {
+            drop(listing);
+            self.reject(&name, "not in the listing");
+            return Err(SphairaError::NotListed(name));
+        };
+        // short reads would leave sphaira waiting on bytes that never come
+        if end > size {
+            drop(listing);
+            self.reject(&name, "range past the end of the file");
+            return Err(SphairaError::RangeOutOfBounds(start, len, size));
+        }
+        let f = storage::open_file_range(path, game, start, end).await?;
+        let (path, title) = (path.to_path_buf(), Title::from(game));
+        drop(listing);
+
+        let interface = self.get_interface();
+        if interface
+            .get_stats()
+            .start_range(&path, size, Some(title), start)
+        {
+            interface.log(format_args!("\"{name}\" requested"));
         }
 
         let file_range_header = CmdPacket::new(CmdType::FileRange.into(), datasize);
-        let tx = self.get_interface_mut().get_tx();
-
-        tx.write(bytes_of(&file_range_header))
-            .await
-            .map_err(SwitchCommError::from)?;
-        tx.flush().await?;
+        let interface = self.get_interface_mut();
+        interface.write_header(&file_range_header).await?;
+        interface.flush().await?;
 
         // write the file
-        loop {
-            if self.write_next_chunk(&mut f).await? == ChunkStatus::End {
-                break;
-            }
-        }
+        self.write_chunks(f).await?;
         Ok(())
     }
+
+    /// sphaira has no error reply - exit's the closest, it ends the install instead of leaving it waiting on data
+    async fn send_exit(&mut self) -> Result<(), SwitchCommError> {
+        let exit = CmdPacket::new(CmdType::Exit.into(), 0);
+        self.get_interface_mut().write_frame(&exit, &[]).await
+    }
+
+    /// logged and audited - the reply's left to poll_command, like any other refusal
+    fn reject(&self, name: &str, reason: &str) {
+        let interface = self.get_interface();
+        interface.log(format_args!("Rejected request for {name:?}: {reason}"));
+        let rejection = Rejection::new(&interface.get_ident().name(), name, reason);
+        if let Err(e) = history::audit(&rejection) {
+            interface.log(format_args!("failed to write audit log: {e}"));
+        }
+    }
 }
@@ -1,11 +1,8 @@
-use std::{io::Write, mem};
-
-use smol::io::AsyncReadExt;
-
+use bytemuck::bytes_of;
 use miniserde::json;
 
 use crate::device::{
-    SwitchCommError, SwitchHost, SwitchHostImpl,
+    CHUNK_SIZE, SwitchCommError, SwitchHost, SwitchHostImpl,
     hosts::tinfoil::{
         packet::{CommandPacket, StatusResponse},
         query::{TinfoilQuery, TinfoilQueryError},
@@ -14,10 +11,10 @@ use crate::device::{
     writer::SwitchHostWriterExt,
 };
 
-mod packet;
+pub mod packet;
 mod query;
 
-const DEFAULT_CMD: u32 = 1; // tinfoil only every has 1
+pub const DEFAULT_CMD: u32 = 1; // tinfoil only every has 1
 
 pub struct TinfoilInterface {
     inner: SwitchInterface,
@@ -29,7 +26,13 @@ impl From<SwitchInterface> for TinfoilInterface {
     }
 }
 
-impl SwitchHostWriterExt for TinfoilInterface {}
+impl SwitchHostWriterExt for TinfoilInterface {
+    const MAX_CHUNK_N: u64 = CHUNK_SIZE; // every chunk's a frame - bigger ones haven't been tried on a console
+
+    fn chunk_header(len: u64) -> Option<Vec<u8>> {
+        Some(bytes_of(&CommandPacket::new(DEFAULT_CMD, len)).to_vec())
+    }
+}
 
 impl SwitchHostImpl for TinfoilInterface {
     fn get_interface_mut(&mut self) -> &mut SwitchInterface {
@@ -46,32 +49,32 @@ impl TinfoilInterface {
     async fn listen_response(&mut self) -> Result<(), SwitchCommError> {
         let interface = self.get_interface_mut();
 
-        let mut recv_buff = [0u8; mem::size_of::<CommandPacket>()];
-        interface.get_rx().read(&mut recv_buff).await?;
-
-        // from_raw panics for size-mismatch but bulk_in already errs if incomplete read - always will be 32 bits
-        // extract all relevant deets - tinfoil makes everything else 0 - so we can reuse recv_buff
-        let &CommandPacket { cmd, size, .. } =
-            CommandPacket::from_raw(&recv_buff).ok_or(SwitchCommError::BadMagic)?;
-
-        if cmd != DEFAULT_CMD {
+        // tinfoil makes everything but cmd and size 0
+        let header = interface.read_header::<CommandPacket>().await?;
+        if header.cmd != DEFAULT_CMD {
             return Err(SwitchCommError::UnknownCmd);
         }
-
-        let mut p = vec![0u8; size as usize];
-        interface.get_rx().read(&mut p).await?;
+        let p = interface.read_payload(&header).await?;
 
         let payload = String::from_utf8(p).map_err(|_| SwitchCommError::CorruptedCmd)?;
         // handle and respond to query
         if let Err(e) = TinfoilQuery::process_query(self, &payload).await {
             match e {
+                // the console's next command is already on its way, answer that instead
+                TinfoilQueryError::CommError(SwitchCommError::Cancelled) => {
+                    self.get_interface().log(format_args!("download cancelled"));
+                }
                 TinfoilQueryError::CommError(com_e) => return Err(com_e),
                 TinfoilQueryError::BadQuery(bq_e) => {
                     let e = bq_e.to_string();
-                    println!("Query error; {}", &e);
+                    self.get_interface().log(format_args!("Query error; {e}"));
+                    let res = json::to_string(&StatusResponse::new(false, e));
                     self.get_interface_mut()
-                        .get_tx()
-                        .write(&json::to_string(&StatusResponse::new(false, e)).as_bytes())?;
+                        .write_frame(
+                            &CommandPacket::new(DEFAULT_CMD, res.len() as u64),
+                            res.as_bytes(),
+                        )
+                        .await?;
                 }
             }
         };
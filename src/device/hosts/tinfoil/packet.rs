@@ -1,7 +1,10 @@
 use bytemuck::{Pod, Zeroable, from_bytes};
 
+use crate::device::codec::Header;
+
 // tinfoil's magic header
 const MAGIC_HEADER: [u8; 4] = [0x12, 0x12, 0x12, 0x12];
+const MAX_QUERY_N: u64 = 0x10000; // queries are urls - a few hundred bytes at most
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Pod, Zeroable, Default)]
@@ -50,3 +53,15 @@ impl CommandPacket {
         }
     }
 }
+
+impl Header for CommandPacket {
+    const MAX_PAYLOAD_N: u64 = MAX_QUERY_N;
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC_HEADER
+    }
+
+    fn payload_len(&self) -> u64 {
+        self.size
+    }
+}
@@ -1,22 +1,18 @@
 /*
 info, queue, search, download
 */
-use smol::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt},
-};
-use std::io::{self, SeekFrom};
+use std::io;
 
-use bytemuck::bytes_of;
 use miniserde::json;
-use smol::io::AsyncWriteExt;
 use thiserror::Error;
 
 use crate::{
     device::{
-        CHUNK_SIZE, SwitchCommError, SwitchHostImpl,
+        SwitchCommError, SwitchHostImpl,
         hosts::tinfoil::{DEFAULT_CMD, TinfoilInterface, packet::CommandPacket},
-        writer::{ChunkStatus, SwitchHostWriterExt},
+        progress::Title,
+        storage,
+        writer::SwitchHostWriterExt,
     },
     game::entry::GameEntry,
     listing::ListingIndex,
@@ -36,6 +32,8 @@ pub enum TinfoilQueryErrorKind {
     GameNotFound(String),
     #[error("bad download range")]
     BadRange,
+    #[error("range {0}..{1} is past the end of the file ({2} bytes)")]
+    RangeOutOfBounds(u64, u64, u64),
     #[error("failed to read file: {0}")]
     FileRead(#[from] io::Error),
 }
@@ -69,7 +67,7 @@ impl<'a> TinfoilQuery<'a> {
         device: &'a mut TinfoilInterface,
         payload: &'a str,
     ) -> Result<Self, TinfoilQueryError> {
-        let mut args = payload.split('/');
+        let mut args = payload.splitn(4, '/'); // query keeps its own slashes (download ranges)
         if args.next() != Some("") {
             return Err(TinfoilQueryErrorKind::UnsupportedCmd(payload.to_string()))?;
             // be somewhat strict; tinfoil over usb will always have '/' at start
@@ -105,38 +103,38 @@ impl<'a> TinfoilQuery<'a> {
 }
 
 impl TinfoilQuery<'_> {
-    async fn write_bytes(&mut self, buf: &[u8]) -> Result<(), TinfoilQueryError> {
-        let tx = self.device.get_interface_mut().get_tx();
-        tx.write(buf).await.map_err(SwitchCommError::from)?;
-        tx.flush().await.map_err(SwitchCommError::from)?;
-        Ok(())
-    }
-
     async fn write_str(&mut self, res: &str) -> Result<(), TinfoilQueryError> {
-        self.write_bytes(&bytes_of(&CommandPacket::new(
-            DEFAULT_CMD,
-            res.len() as u64,
-        )))
-        .await?;
-        self.write_bytes(res.as_bytes()).await?;
+        self.device
+            .get_interface_mut()
+            .write_frame(
+                &CommandPacket::new(DEFAULT_CMD, res.len() as u64),
+                res.as_bytes(),
+            )
+            .await?;
         Ok(())
     }
 }
 
 impl TinfoilQuery<'_> {
     async fn route_api(mut self) -> Result<(), TinfoilQueryError> {
-        println!(
-            "API request - req: {}, queries: {:?}",
-            self.req_type, self.query
-        );
+        let req_type = self.req_type.trim_end_matches('?'); // residue of html query impl in tinfoil it seems
+
+        // downloads come in by the hundred - progress covers those
+        if req_type != "download" {
+            self.device.get_interface().log(format_args!(
+                "API request - req: {}, queries: {:?}",
+                self.req_type, self.query
+            ));
+        }
 
-        match self.req_type.trim_end_matches('?') // residue of html query impl in tinfoil it seems
-        {
+        match req_type {
             "queue" => self.handle_queue().await,
             "search" => self.handle_search().await,
             "info" => self.handle_info().await,
             "download" => self.handle_download().await, // because my TinfoilQueryError also maps from io::Error
-            _ => Err(TinfoilQueryErrorKind::UnsupportedReqType(self.req_type.to_string()))?,
+            _ => Err(TinfoilQueryErrorKind::UnsupportedReqType(
+                self.req_type.to_string(),
+            ))?,
         }?;
         Ok(())
     }
@@ -166,28 +164,30 @@ impl TinfoilQuery<'_> {
         if start > end {
             return Err(TinfoilQueryErrorKind::BadRange)?;
         }
+        if end > game.size() {
+            return Err(TinfoilQueryErrorKind::RangeOutOfBounds(
+                start,
+                end,
+                game.size(),
+            ))?;
+        }
 
-        let mut f = File::open(game.path())
-            .await
-            .map_err(TinfoilQueryErrorKind::from)?;
-
-        f.seek(SeekFrom::Start(start))
+        let f = storage::open_game_range(game, start, end)
             .await
             .map_err(TinfoilQueryErrorKind::from)?;
 
-        println!("Requested file: {:?}, range {start}-{end}", game.path());
+        let interface = self.device.get_interface();
+        if interface.get_stats().start_range(
+            game.path(),
+            game.size(),
+            Some(Title::from(game)),
+            start,
+        ) {
+            interface.log(format_args!("{:?} requested", game.path()));
+        }
         drop(listing);
 
-        let header = CommandPacket::new(DEFAULT_CMD, CHUNK_SIZE);
-
-        let mut f = f.take(end - start);
-
-        loop {
-            self.write_bytes(&bytes_of(&header)).await?;
-            if self.device.write_next_chunk(&mut f).await? == ChunkStatus::End {
-                break;
-            }
-        }
+        self.device.write_chunks(f).await?;
         Ok(())
     }
 
@@ -1,20 +1,25 @@
-use std::{sync::Arc, time::Duration};
+use std::{fmt, io, sync::Arc, time::Duration};
 
-use nusb::{
-    Device, DeviceInfo, Interface,
-    hotplug::HotplugEvent,
-    io::{EndpointRead, EndpointWrite},
-    transfer::{Bulk, Direction},
-};
+use nusb::{Device, DeviceId, DeviceInfo};
 use smol::{
-    Timer,
+    Timer, future,
+    io::{AsyncReadExt, AsyncWriteExt},
     lock::{RwLock, RwLockReadGuard},
-    stream::StreamExt,
 };
 use thiserror::Error;
 
 use crate::{
-    device::{CONNECTED_IDS, DEVICES, RX_BUFF_N, TX_BUFF_N},
+    device::{
+        CONNECTED_IDS, IDLE_TIMEOUT, RX_BUFF_N, RX_TIMEOUT, SwitchCommError, TX_TIMEOUT,
+        filter::device_allowed,
+        progress::clear_line,
+        session::SessionStats,
+        shutdown,
+        throttle::Throttle,
+        timeout,
+        transport::{DeviceIdent, SwitchTransport, TransportTx, usb::UsbTransport},
+        writer::initial_chunk_size,
+    },
     listing::Listing,
 };
 
@@ -29,12 +34,19 @@ pub enum SwitchInitError {
 }
 
 pub struct SwitchInterface {
-    device: Device,
-    device_info: DeviceInfo,
-    interface: Interface, // tinfoil's interface - there's only one really...
-    rx: EndpointRead<Bulk>,
-    tx: EndpointWrite<Bulk>,
+    transport: Box<dyn SwitchTransport>, // usb in the wild, pipes for the emulator
     listing: Arc<smol::lock::RwLock<Listing>>,
+    unread: Vec<u8>, // bytes we've peeked at - handed out again before touching rx
+    stats: Arc<SessionStats>,
+    chunk_size: u64, // tuned by the writer as transfers go
+    throttle: Throttle,
+}
+
+/// how a write mid-transfer ended - see write_transfer
+enum Watched {
+    Sent(io::Result<()>),
+    Heard(io::Result<usize>), // the console said something instead of reading
+    Stalled,                  // neither - slow storage on its end, or it's gone, see watched
 }
 
 async fn open_device(device_info: &DeviceInfo) -> Option<Device> {
@@ -44,11 +56,7 @@ async fn open_device(device_info: &DeviceInfo) -> Option<Device> {
         return None;
     }
 
-    if DEVICES
-        .iter()
-        .find(|d| d.prod == device_info.product_id() && d.vendor == device_info.vendor_id())
-        .is_none()
-    {
+    if !device_allowed(device_info) {
         None
     } else {
         // usb errors aren't fatal
@@ -64,85 +72,288 @@ async fn open_device(device_info: &DeviceInfo) -> Option<Device> {
     }
 }
 
-pub async fn get_conn() -> Result<(Device, DeviceInfo), SwitchInitError> {
-    for d_info in nusb::list_devices().await? {
-        if let Some(d) = open_device(&d_info).await {
-            return Ok((d, d_info));
+/// lets a device with this id be picked up again
+pub async fn release_device(id: DeviceId) {
+    CONNECTED_IDS.lock().await.retain(|i| *i != id);
+}
+
+impl SwitchInterface {
+    pub fn new<T: SwitchTransport + 'static>(transport: T, listing: Arc<RwLock<Listing>>) -> Self {
+        let stats = Arc::new(SessionStats::new(transport.ident().clone()));
+        let chunk_size = initial_chunk_size(transport.max_packet_size());
+        Self {
+            transport: Box::new(transport),
+            listing,
+            unread: Vec::new(),
+            stats,
+            chunk_size,
+            throttle: Throttle::default(),
         }
     }
 
-    let mut watcher = nusb::watch_devices()?;
+    /// None if the device isn't a switch or is already being served
+    /// `recovering` for a device whose last session wedged
+    pub async fn open(
+        device_info: &DeviceInfo,
+        listing: Arc<RwLock<Listing>>,
+        recovering: bool,
+    ) -> Result<Option<Self>, SwitchInitError> {
+        let Some(device) = open_device(device_info).await else {
+            return Ok(None);
+        };
 
-    // wait for connection
-    loop {
-        if let Some(HotplugEvent::Connected(d_info)) = watcher.next().await
-            && let Some(d) = open_device(&d_info).await
-        {
-            return Ok((d, d_info));
+        match UsbTransport::open(device, device_info, recovering).await {
+            Ok(transport) => Ok(Some(Self::new(transport, listing))),
+            Err(e) => {
+                release_device(device_info.id()).await; // we never got it, so don't keep it reserved
+                Err(e)
+            }
         }
     }
+
+    pub async fn get_listing(&self) -> RwLockReadGuard<'_, Listing> {
+        self.listing.read().await
+    }
+
+    pub fn get_ident(&self) -> &DeviceIdent {
+        self.transport.ident()
+    }
+
+    pub fn get_stats(&self) -> &Arc<SessionStats> {
+        &self.stats
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn set_chunk_size(&mut self, n: u64) {
+        self.chunk_size = n;
+    }
+
+    /// println, tagged with the device so concurrent sessions can be told apart
+    pub fn log(&self, args: fmt::Arguments) {
+        clear_line();
+        println!("[{}] {args}", self.get_ident().name());
+    }
+
+    /// puts bytes back so the next read sees them first
+    pub fn unread(&mut self, buf: &[u8]) {
+        self.unread.splice(0..0, buf.iter().copied());
+    }
+
+    /// what'll be read next, if it's already been read once
+    pub fn peek(&self) -> &[u8] {
+        &self.unread
+    }
+
+    /// fills as much of buf as we have unread bytes for
+    fn take_unread(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.unread.len());
+        buf[..n].copy_from_slice(&self.unread[..n]);
+        self.unread.drain(..n);
+        n
+    }
 }
 
+// timed wrappers - use these over raw get_rx/get_tx so a wedged transfer errors instead of hanging
 impl SwitchInterface {
-    pub async fn wait_new(listing: Arc<RwLock<Listing>>) -> Result<Self, SwitchInitError> {
-        let (device, device_info) = get_conn().await?;
-
-        // following not important as tinfoil interface has one config anyways - these aren't supported on windows/WinUSB
-        // device.reset()?;
-        // let (device, device_info) = get_conn().await?;
-
-        // tinfoil's usb interface - one config, 2 interfaces but still try to be dynamic...
-        // device.set_configuration(1)?;
-
-        let interface = match device.claim_interface(0).await {
-            Ok(i) => i,
-            Err(_) => {
-                // for windows, interfaces might only be available after a small delay
-                Timer::after(Duration::from_millis(500)).await;
-                device.claim_interface(0).await?
+    /// waits as long as it takes for the console's next command, checking in on it whenever it goes quiet
+    /// gives up with ShuttingDown if that's asked for in the meantime
+    pub async fn read_command(&mut self, buf: &mut [u8]) -> Result<(), SwitchCommError> {
+        let mut filled = self.take_unread(buf);
+        while filled < buf.len() {
+            let read = async { Ok(self.transport.rx().read(&mut buf[filled..]).await?) };
+            let stop = async {
+                shutdown::stopped().await;
+                Err(SwitchCommError::ShuttingDown)
+            };
+
+            // a read that hasn't returned yet hasn't consumed anything, so it's safe to drop on timeout
+            let Some(n) = timeout(IDLE_TIMEOUT, future::or(stop, read)).await else {
+                timeout(IDLE_TIMEOUT, self.transport.keepalive())
+                    .await
+                    .and_then(|r| r.ok())
+                    .ok_or(SwitchCommError::Unresponsive)?;
+                continue;
+            };
+
+            match n? {
+                0 => return Err(SwitchCommError::Disconnected),
+                n => filled += n,
             }
-        }; // why are there 2 interfaces anyways...
-
-        let a_set = interface
-            .descriptors()
-            .find(|d| d.alternate_setting() == 0)
-            .ok_or(SwitchInitError::NoInterface)?;
-
-        // could've hardcoded addresses but future-proofing
-        let out_ep = a_set
-            .endpoints()
-            .find(|ep| ep.direction() == Direction::Out)
-            .ok_or(SwitchInitError::EpNotFound)?
-            .address();
-
-        let in_ep = a_set
-            .endpoints()
-            .find(|ep| ep.direction() == Direction::In)
-            .ok_or(SwitchInitError::EpNotFound)?
-            .address();
-
-        let tx = EndpointWrite::new(interface.endpoint::<Bulk, _>(out_ep)?, TX_BUFF_N);
-        let rx = EndpointRead::new(interface.endpoint::<Bulk, _>(in_ep)?, RX_BUFF_N);
-
-        Ok(Self {
-            device,
-            device_info,
-            interface,
-            tx,
-            rx,
-            listing,
-        })
+        }
+        Ok(())
     }
 
-    pub async fn get_listing(&self) -> RwLockReadGuard<'_, Listing> {
-        self.listing.read().await
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), SwitchCommError> {
+        let filled = self.take_unread(buf);
+        timeout(
+            RX_TIMEOUT,
+            self.transport.rx().read_exact(&mut buf[filled..]),
+        )
+        .await
+        .ok_or(SwitchCommError::Timeout)??;
+        Ok(())
     }
 
-    pub fn get_rx(&mut self) -> &mut EndpointRead<Bulk> {
-        &mut self.rx
+    /// like read_exact, but Ok(false) (nothing consumed) if the console stays quiet for `dur`
+    pub async fn read_within(
+        &mut self,
+        buf: &mut [u8],
+        dur: Duration,
+    ) -> Result<bool, SwitchCommError> {
+        let filled = match self.take_unread(buf) {
+            0 => match timeout(dur, self.transport.rx().read(buf)).await {
+                None => return Ok(false),
+                Some(Ok(0)) => return Err(SwitchCommError::Disconnected),
+                Some(n) => n?,
+            },
+            n => n,
+        };
+        self.read_exact(&mut buf[filled..]).await?;
+        Ok(true)
     }
 
-    pub fn get_tx(&mut self) -> &mut EndpointWrite<Bulk> {
-        &mut self.tx
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<(), SwitchCommError> {
+        self.watched(Some(buf), false).await
+    }
+
+    pub fn submit(&mut self) {
+        self.transport.submit();
+    }
+
+    pub fn submit_end(&mut self) {
+        self.transport.submit_end();
+    }
+
+    /// write_all for file data - errs with Cancelled if the console gives up on the range part way through
+    pub async fn write_transfer(&mut self, buf: &[u8]) -> Result<(), SwitchCommError> {
+        self.watched(Some(buf), true).await
+    }
+
+    /// flush for file data, see write_transfer
+    pub async fn flush_transfer(&mut self) -> Result<(), SwitchCommError> {
+        self.watched(None, true).await
+    }
+
+    /// a console that's cancelled stops reading and sends its next command, so `listen` while writing - if it does,
+    /// the rest of the range is dropped and the command's read as normal
+    /// one that just goes quiet may only be busy (sd card stalls, nsz decompression) - it still answers a keepalive,
+    /// and is waited on for as long as that holds. one that doesn't is Unresponsive
+    async fn watched(&mut self, buf: Option<&[u8]>, listen: bool) -> Result<(), SwitchCommError> {
+        let mut heard = [0u8; RX_BUFF_N];
+        let mut written = 0;
+        loop {
+            let (rx, tx) = self.transport.split();
+            let send = async {
+                Watched::Sent(match buf {
+                    Some(buf) => write_from(tx, buf, &mut written).await,
+                    None => tx.flush().await,
+                })
+            };
+            let hear = async {
+                match listen {
+                    true => Watched::Heard(rx.read(&mut heard).await),
+                    false => future::pending().await,
+                }
+            };
+            let stall = async {
+                Timer::after(TX_TIMEOUT).await;
+                Watched::Stalled
+            };
+
+            match future::or(future::or(send, hear), stall).await {
+                Watched::Sent(res) => return Ok(res?),
+                Watched::Heard(Err(e)) => return Err(e.into()),
+                // start of the next command (or a zlp, or the pipe closing - read_command sorts that out)
+                Watched::Heard(Ok(n)) => {
+                    self.unread.extend_from_slice(&heard[..n]);
+                    break;
+                }
+                Watched::Stalled => timeout(IDLE_TIMEOUT, self.transport.keepalive())
+                    .await
+                    .and_then(|r| r.ok())
+                    .ok_or(SwitchCommError::Unresponsive)?,
+            }
+        }
+
+        timeout(TX_TIMEOUT, self.transport.abort_tx())
+            .await
+            .ok_or(SwitchCommError::Timeout)??;
+        Err(SwitchCommError::Cancelled)
+    }
+
+    /// holds off until the rate limits allow n more bytes - `free` ones get some slack, see throttle
+    pub async fn pace(&mut self, n: u64, free: bool) {
+        let wait = self.throttle.take(n, free);
+        if !wait.is_zero() {
+            Timer::after(wait).await;
+        }
+    }
+
+    pub async fn flush(&mut self) -> Result<(), SwitchCommError> {
+        self.watched(None, false).await
+    }
+}
+
+/// write_all that keeps count in `written`, so it can be dropped part way and picked up again
+/// (a write that hasn't returned yet hasn't taken anything)
+async fn write_from(tx: &mut TransportTx, buf: &[u8], written: &mut usize) -> io::Result<()> {
+    while *written < buf.len() {
+        match tx.write(&buf[*written..]).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => *written += n,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::transport::pipe::{PipeTransport, duplex},
+        scan::ScanRules,
+    };
+
+    fn connect() -> (SwitchInterface, PipeTransport) {
+        let (host_end, console_end) = duplex();
+        let listing = Arc::new(RwLock::new(Listing::new(ScanRules::default())));
+        (SwitchInterface::new(host_end, listing), console_end)
+    }
+
+    #[test]
+    fn busy_switch_is_waited_on() {
+        // stops reading for a few TX_TIMEOUTs (an nsz being unpacked) - still there, so nothing's lost or restarted
+        let (mut host, mut console) = connect();
+        let data = (0..0x40000)
+            .map(|i| (i ^ (i >> 8)) as u8)
+            .collect::<Vec<_>>();
+        let mut received = vec![0u8; data.len()];
+
+        let (sent, read) = smol::block_on(future::zip(
+            async {
+                host.write_transfer(&data).await?;
+                host.flush_transfer().await
+            },
+            async {
+                Timer::after(TX_TIMEOUT * 3).await;
+                console.rx().read_exact(&mut received).await
+            },
+        ));
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn quiet_switch_is_given_up_on() {
+        let (mut host, console) = connect();
+        console.go_quiet();
+
+        let e = smol::block_on(host.write_all(&[0u8; 0x40000])).unwrap_err();
+        assert!(matches!(e, SwitchCommError::Unresponsive));
+        assert!(e.is_recoverable());
     }
 }
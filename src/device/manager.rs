@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use nusb::{DeviceId, DeviceInfo, hotplug::HotplugEvent};
+use smol::{
+    Executor, Task,
+    channel::{Sender, unbounded},
+    lock::RwLock,
+    stream::{self, StreamExt},
+};
+
+use crate::{
+    device::{
+        SwitchCommError, UsbClient,
+        interface::{SwitchInitError, SwitchInterface, release_device},
+        progress,
+        session::{SessionRegistry, SessionStats},
+        shutdown::{self, DRAIN_TIMEOUT},
+        timeout,
+    },
+    listing::Listing,
+};
+
+const MAX_RECOVERIES: u32 = 3; // in a row - past this the switch is probably not coming back by itself
+const RECOVERY_WINDOW: Duration = Duration::from_secs(60); // sessions that lived longer than this reset the count
+
+enum ManagerEvent {
+    Hotplug(HotplugEvent),
+    SessionEnded(DeviceId, bool), // bool: worth recovering
+    Shutdown,
+}
+
+struct Session {
+    stats: Arc<SessionStats>,
+    info: DeviceInfo,
+    started: Instant,
+    recoveries: u32,
+    _task: Task<()>, // dropping cancels the session
+}
+
+/// Owns every session - one hotplug watcher for the whole program
+pub struct DeviceManager {
+    listing: Arc<RwLock<Listing>>,
+    client: Option<UsbClient>, // None -> work it out per device
+    sessions: HashMap<DeviceId, Session>,
+    registry: SessionRegistry,
+}
+
+impl DeviceManager {
+    pub fn new(listing: Arc<RwLock<Listing>>, client: Option<UsbClient>) -> Self {
+        Self {
+            listing,
+            client,
+            sessions: HashMap::new(),
+            registry: SessionRegistry::default(),
+        }
+    }
+
+    /// drops the session (cancelling it if it's still running) and frees the device up
+    async fn end_session(&mut self, id: DeviceId) -> Option<Session> {
+        release_device(id).await; // replugging should bring it back
+        let session = self.sessions.remove(&id)?;
+
+        self.registry.remove(session.stats.ident()).await;
+        session.stats.end();
+        println!("Session ended; {}", session.stats.summary());
+        self.registry.report().await;
+        Some(session)
+    }
+
+    /// runs until shutdown; spawning a session per connected switch and tearing it down on disconnect
+    /// on shutdown no new switches are picked up, and sessions get DRAIN_TIMEOUT to stop by themselves before they're dropped
+    pub async fn run(mut self, executor: &Executor<'_>) -> Result<(), SwitchInitError> {
+        // watch before listing so a device can't slip in between the two
+        let watcher = nusb::watch_devices()?;
+        let (ended_tx, ended_rx) = unbounded();
+        let _progress = executor.spawn(progress::report(self.registry.clone()));
+
+        for d_info in nusb::list_devices().await? {
+            self.connect(&d_info, executor, &ended_tx, 0).await;
+        }
+
+        let mut events = pin!(
+            watcher
+                .map(ManagerEvent::Hotplug)
+                .or(ended_rx.map(|(id, recover)| ManagerEvent::SessionEnded(id, recover)))
+                .or(stream::once_future(shutdown::stopped()).map(|_| ManagerEvent::Shutdown))
+        );
+
+        let mut draining = None; // deadline, once shutting down
+        loop {
+            let wait = draining.map_or(Duration::MAX, |d: Instant| {
+                d.saturating_duration_since(Instant::now())
+            });
+            let Some(Some(event)) = timeout(wait, events.next()).await else {
+                break; // out of time
+            };
+
+            match event {
+                ManagerEvent::Shutdown => {
+                    println!("Waiting on {} session(s) to stop", self.sessions.len());
+                    draining = Some(Instant::now() + DRAIN_TIMEOUT);
+                }
+                ManagerEvent::Hotplug(HotplugEvent::Connected(_)) if draining.is_some() => (),
+                ManagerEvent::Hotplug(HotplugEvent::Connected(d_info)) => {
+                    self.connect(&d_info, executor, &ended_tx, 0).await
+                }
+                ManagerEvent::Hotplug(HotplugEvent::Disconnected(id)) => {
+                    self.end_session(id).await;
+                }
+                ManagerEvent::SessionEnded(id, recover) => {
+                    let Some(session) = self.end_session(id).await else {
+                        continue; // already torn down by a disconnect
+                    };
+
+                    if !recover || draining.is_some() {
+                        continue;
+                    }
+
+                    let recoveries = if session.started.elapsed() > RECOVERY_WINDOW {
+                        1
+                    } else {
+                        session.recoveries + 1
+                    };
+                    if recoveries > MAX_RECOVERIES {
+                        println!("Giving up on {} - replug to retry", session.stats.ident());
+                        continue;
+                    }
+
+                    println!(
+                        "Recovering {} ({recoveries}/{MAX_RECOVERIES})",
+                        session.stats.ident()
+                    );
+                    self.connect(&session.info, executor, &ended_tx, recoveries)
+                        .await;
+                }
+            }
+
+            if draining.is_some() && self.sessions.is_empty() {
+                break;
+            }
+        }
+
+        // whatever hasn't stopped by now gets cut off - dropping the session releases its interface
+        let ids = self.sessions.keys().copied().collect::<Vec<_>>();
+        if !ids.is_empty() {
+            println!("Stopping {} session(s) mid-transfer", ids.len());
+        }
+        for id in ids {
+            self.end_session(id).await;
+        }
+        Ok(())
+    }
+
+    /// `recoveries` > 0 when re-claiming a device whose session wedged
+    async fn connect(
+        &mut self,
+        d_info: &DeviceInfo,
+        executor: &Executor<'_>,
+        ended: &Sender<(DeviceId, bool)>,
+        recoveries: u32,
+    ) {
+        let device = match SwitchInterface::open(d_info, self.listing.clone(), recoveries > 0).await
+        {
+            Ok(Some(d)) => d,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Error connecting: {e:?}");
+                return;
+            }
+        };
+
+        let stats = device.get_stats().clone();
+        println!("Connected! {}", stats.ident());
+        self.registry.insert(stats.clone()).await;
+        self.registry.report().await;
+
+        let id = d_info.id();
+        let client = self.client;
+        let ended = ended.clone();
+        let ident = stats.ident().clone();
+        let task = executor.spawn(async move {
+            let recover = match UsbClient::serve(client, device).await {
+                Ok(()) | Err(SwitchCommError::ShuttingDown) => false,
+                Err(e) => {
+                    eprintln!("[{}] {e:?} (switch disconnected?)", ident.name());
+                    e.is_recoverable()
+                }
+            };
+            let _ = ended.send((id, recover)).await;
+        });
+
+        self.sessions.insert(
+            id,
+            Session {
+                stats,
+                info: d_info.clone(),
+                started: Instant::now(),
+                recoveries,
+                _task: task,
+            },
+        );
+    }
+}
@@ -1,40 +1,47 @@
+pub mod codec;
+pub mod filter;
 pub mod hosts;
 pub mod interface;
-mod writer;
+pub mod manager;
+pub mod progress;
+pub mod session;
+pub mod shutdown;
+pub mod storage;
+pub mod throttle;
+pub mod transport;
+pub mod writer;
 // mod hosts;
 
-use std::{fmt::Display, io, sync::LazyLock};
+use std::{fmt::Display, io, mem, sync::LazyLock, time::Duration};
 
 use nusb::{DeviceId, transfer::TransferError};
-use smol::lock::Mutex;
+use smol::{Timer, future, lock::Mutex};
 use thiserror::Error;
 
 use crate::device::{
-    hosts::{sphaira::SphairaInterface, tinfoil::TinfoilInterface},
+    hosts::{
+        sphaira::SphairaInterface,
+        tinfoil::{TinfoilInterface, packet::CommandPacket},
+    },
     interface::SwitchInterface,
 };
 
-const CHUNK_SIZE: u64 = 0x800000; // ~4mb - good chunk size
-const FILE_CACHE_N: usize = 50; // keep n chunk sizes in memory to reduce disk reads
+pub const CHUNK_SIZE: u64 = 0x800000; // ~4mb - good chunk size
+const FILE_CACHE_N: usize = 50; // keep n chunk sizes in memory to reduce disk reads - see storage
 
 // TX/RX
-const TX_BUFF_N: usize = CHUNK_SIZE as usize;
+const TX_TRANSFER_N: usize = 0x100000; // 1mb per bulk transfer, chunks are split over several
+const TX_TRANSFERS_N: usize = 8; // queued with the os at once, so the bus isn't idle between completions
 const RX_BUFF_N: usize = 100; // 100 bytes more than sufficient I'd say
 
-struct HostId {
-    vendor: u16,
-    prod: u16,
-}
-const DEVICES: [HostId; 2] = [
-    HostId {
-        vendor: 0x16C0,
-        prod: 0x27E2,
-    },
-    HostId {
-        vendor: 0x057E,
-        prod: 0x3000,
-    },
-];
+// timeouts - a hung transfer shouldn't hang the session
+const RX_TIMEOUT: Duration = Duration::from_secs(5); // rest of a packet once it's started arriving
+#[cfg(not(test))]
+const TX_TIMEOUT: Duration = Duration::from_secs(30); // write stuck this long -> check the switch is still alive
+#[cfg(test)]
+const TX_TIMEOUT: Duration = Duration::from_millis(200); // tests sit through a few
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30); // quiet this long -> check the switch is still alive
+pub const DETECT_TIMEOUT: Duration = Duration::from_secs(2); // tinfoil asks for the listing straight away
 
 // purely an internal variable so not difficult to reason about this
 static CONNECTED_IDS: LazyLock<Mutex<Vec<DeviceId>>> = LazyLock::new(|| Mutex::new(vec![]));
@@ -49,9 +56,57 @@ pub enum SwitchCommError {
     UnknownCmd, // tinfoil only has command == 1
     #[error("bad utf-8 in cmd")]
     CorruptedCmd,
+    #[error("{0} byte payload, at most {1} allowed")]
+    PayloadTooLarge(u64, u64), // can't be skipped over, so the stream's lost
     // following should be fatal
     #[error("payload r/w failed")]
-    SwitchRw(#[from] io::Error),
+    SwitchRw(io::Error),
+    #[error("transfer timed out")]
+    Timeout,
+    #[error("endpoint stalled")]
+    Stalled,
+    #[error("switch unresponsive")]
+    Unresponsive,
+    #[error("switch disconnected")]
+    Disconnected,
+    #[error("transfer cancelled by the switch")]
+    Cancelled, // not fatal - the rest of the range is dropped and the session carries on
+    #[error("shutting down")]
+    ShuttingDown,
+}
+
+impl From<io::Error> for SwitchCommError {
+    fn from(e: io::Error) -> Self {
+        // nusb folds TransferErrors into io::Error kinds, unfold the ones we act on
+        match e.kind() {
+            io::ErrorKind::ConnectionReset => Self::Stalled,
+            io::ErrorKind::ConnectionAborted => Self::Disconnected,
+            io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::SwitchRw(e),
+        }
+    }
+}
+
+impl SwitchCommError {
+    /// worth re-claiming the device over - the switch is (probably) still there
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            Self::Timeout
+                | Self::Stalled
+                | Self::Unresponsive
+                | Self::RecvError(TransferError::Stall)
+        )
+    }
+}
+
+/// None if `f` didn't finish in time
+async fn timeout<T>(dur: Duration, f: impl Future<Output = T>) -> Option<T> {
+    future::or(async { Some(f.await) }, async {
+        Timer::after(dur).await;
+        None
+    })
+    .await
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -84,13 +139,43 @@ impl TryFrom<&str> for UsbClient {
     }
 }
 
-impl Default for UsbClient {
-    fn default() -> Self {
-        Self::Tinfoil
+impl UsbClient {
+    /// Tinfoil speaks first, Sphaira waits for our file list - so a quiet switch is Sphaira
+    pub async fn detect(device: &mut SwitchInterface) -> Result<Self, SwitchCommError> {
+        let mut buf = [0u8; mem::size_of::<CommandPacket>()];
+        if !device.read_within(&mut buf, DETECT_TIMEOUT).await? {
+            return Ok(Self::Sphaira);
+        }
+
+        device.unread(&buf); // tinfoil still needs to see its first command
+        match CommandPacket::from_raw(&buf) {
+            Some(_) => Ok(Self::Tinfoil),
+            None => Err(SwitchCommError::BadMagic),
+        }
+    }
+
+    /// `client` None -> detect it from the first exchange
+    pub async fn serve(
+        client: Option<Self>,
+        mut device: SwitchInterface,
+    ) -> Result<(), SwitchCommError> {
+        let (client, detected) = match client {
+            Some(c) => (c, false),
+            None => (Self::detect(&mut device).await?, true),
+        };
+        device.get_stats().set_protocol(client);
+        device.log(format_args!("running {client}"));
+        match (client, detected) {
+            // a tinfoil that was slow to speak up gets handed over once it does
+            (Self::Sphaira, true) => {
+                SphairaInterface::detected(device)
+                    .start_talkin_buddy()
+                    .await
+            }
+            _ => client.start_interface(device).await,
+        }
     }
-}
 
-impl UsbClient {
     pub async fn start_interface(&self, device: SwitchInterface) -> Result<(), SwitchCommError> {
         match self {
             Self::Tinfoil => TinfoilInterface::from(device).start_talkin_buddy().await,
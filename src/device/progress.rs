@@ -0,0 +1,315 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use smol::{Timer, stream::StreamExt};
+
+use crate::{
+    device::{
+        session::{SessionRegistry, human_bytes, human_duration},
+        transport::DeviceIdent,
+    },
+    game::Game,
+};
+
+/*
+Per (device, file) progress - bytes actually sent vs the file's size, plus throughput and ETA
+Ranges are merged, so an installer re-reading headers doesn't push a file past 100%
+Rendered every TICK: one line redrawn in place on a tty, a log line per device every LOG_INTERVAL otherwise
+Every byte covered -> completed. Console moving on to another file (or going away) part way through -> abandoned
+*/
+
+const TICK: Duration = Duration::from_secs(1);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+const RATE_WINDOW: Duration = Duration::from_secs(5); // throughput is averaged over this long
+const ACTIVE_WINDOW: Duration = Duration::from_secs(5); // nothing sent for this long -> not shown
+const PROBE_N: u64 = 0x100000; // less than this covered is header reads, not an install - never abandoned
+
+static IS_TTY: LazyLock<bool> = LazyLock::new(|| io::stdout().is_terminal());
+
+/// Sorted, non-overlapping [start, end) ranges
+#[derive(Default)]
+pub struct Coverage(Vec<(u64, u64)>);
+
+/// Which title a file is - unknown for files sphaira asks for that aren't in the listing
+#[derive(Debug, Clone)]
+pub struct Title {
+    pub id: String,
+    pub version: String,
+}
+
+pub struct FileProgress {
+    path: PathBuf,
+    size: u64,
+    title: Option<Title>,
+    coverage: Coverage,
+    cursor: u64, // where the next sent bytes land
+    sent: u64,   // including anything sent twice
+    started_at: SystemTime,
+    started: Instant,
+    last_sent: Instant,
+    samples: VecDeque<(Instant, u64)>, // sends within RATE_WINDOW
+}
+
+impl Coverage {
+    pub fn add(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        // everything touching [start, end) folds into one range
+        let first = self.0.partition_point(|&(_, e)| e < start);
+        let last = self.0.partition_point(|&(s, _)| s <= end);
+        let merged = self.0[first..last]
+            .iter()
+            .fold((start, end), |(s, e), &(rs, re)| (s.min(rs), e.max(re)));
+        self.0.splice(first..last, [merged]);
+    }
+
+    pub fn covered(&self) -> u64 {
+        self.0.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// everything in [0, size) has been sent
+    pub fn is_complete(&self, size: u64) -> bool {
+        self.0.first().is_some_and(|&(s, e)| s == 0 && e >= size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Completed,
+    Abandoned,
+}
+
+/// A file this session is done with, one way or the other
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub path: PathBuf,
+    pub size: u64,
+    pub title: Option<Title>,
+    pub covered: u64,
+    pub sent: u64,
+    pub started: SystemTime,
+    pub ended: SystemTime,
+    pub outcome: Outcome,
+}
+
+impl From<&Game> for Title {
+    fn from(game: &Game) -> Self {
+        let info = game.game_info();
+        Self {
+            id: info.title_id().to_string(),
+            version: info.version().to_string(),
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Completed => write!(f, "completed"),
+            Self::Abandoned => write!(f, "abandoned"),
+        }
+    }
+}
+
+impl Display for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .path
+            .file_name()
+            .map_or(String::new(), |f| f.to_string_lossy().to_string());
+        let took = self.ended.duration_since(self.started).unwrap_or_default();
+        write!(
+            f,
+            "{} {name} - {}/{} in {} ({} sent)",
+            self.outcome,
+            human_bytes(self.covered),
+            human_bytes(self.size),
+            human_duration(took),
+            human_bytes(self.sent)
+        )
+    }
+}
+
+impl FileProgress {
+    pub fn new(path: &Path, size: u64, title: Option<Title>) -> Self {
+        let now = Instant::now();
+        Self {
+            path: path.to_path_buf(),
+            size,
+            title,
+            coverage: Coverage::default(),
+            cursor: 0,
+            sent: 0,
+            started_at: SystemTime::now(),
+            started: now,
+            last_sent: now,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn seek(&mut self, start: u64) {
+        self.cursor = start;
+    }
+
+    pub fn advance(&mut self, n: u64) {
+        let now = Instant::now();
+        self.coverage
+            .add(self.cursor, (self.cursor + n).min(self.size));
+        self.cursor += n;
+        self.sent += n;
+        self.last_sent = now;
+
+        self.samples.push_back((now, n));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn covered(&self) -> u64 {
+        self.coverage.covered()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.coverage.is_complete(self.size)
+    }
+
+    /// past the header reads every installer does - stopping now means the install was dropped
+    pub fn is_underway(&self) -> bool {
+        !self.is_complete() && self.covered() >= PROBE_N.min(self.size)
+    }
+
+    pub fn finish(self, outcome: Outcome) -> Transfer {
+        Transfer {
+            covered: self.covered(),
+            path: self.path,
+            size: self.size,
+            title: self.title,
+            sent: self.sent,
+            started: self.started_at,
+            ended: SystemTime::now(),
+            outcome,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.last_sent.elapsed() < ACTIVE_WINDOW
+    }
+
+    /// bytes/s over the last RATE_WINDOW
+    pub fn rate(&self) -> f64 {
+        let now = Instant::now();
+        let window = RATE_WINDOW.min(self.started.elapsed()).as_secs_f64();
+        let recent = self
+            .samples
+            .iter()
+            .filter(|(t, _)| now.duration_since(*t) <= RATE_WINDOW)
+            .map(|(_, n)| n)
+            .sum::<u64>();
+        recent as f64 / window.max(f64::EPSILON)
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (rate >= 1.0).then(|| {
+            Duration::from_secs_f64(self.size.saturating_sub(self.covered()) as f64 / rate)
+        })
+    }
+
+    /// `name 45% (1.2/2.6 GiB) 32.1 MiB/s ETA 1m20s`
+    pub fn line(&self) -> String {
+        let name = self
+            .path
+            .file_name()
+            .map_or(String::new(), |f| f.to_string_lossy().to_string());
+        let percent = self.covered() as f64 * 100.0 / self.size.max(1) as f64;
+        let eta = self.eta().map_or("-".to_string(), human_duration);
+        format!(
+            "{name} {percent:.0}% ({}/{}) {}/s ETA {eta}",
+            human_bytes(self.covered()),
+            human_bytes(self.size),
+            human_bytes(self.rate() as u64),
+        )
+    }
+}
+
+/// wipes the tty progress line so a log line can go where it was - redrawn on the next tick
+pub fn clear_line() {
+    if *IS_TTY {
+        print!("\r\x1b[K");
+    }
+}
+
+/// runs forever, rendering whatever's being transferred
+pub async fn report(registry: SessionRegistry) {
+    let mut ticks = Timer::interval(TICK);
+    let mut last_log = Instant::now();
+    let mut logged = HashMap::<DeviceIdent, u64>::new(); // bytes sent as of the last log line
+    let mut drawn = false;
+
+    while ticks.next().await.is_some() {
+        let sessions = registry.sessions().await;
+        let lines = sessions
+            .iter()
+            .filter_map(|s| Some((s, s.progress_line()?)))
+            .collect::<Vec<_>>();
+
+        if *IS_TTY {
+            if !lines.is_empty() || drawn {
+                let line = lines
+                    .iter()
+                    .map(|(s, l)| format!("[{}] {l}", s.ident().name()))
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                print!("\r\x1b[K{line}");
+                let _ = io::stdout().flush();
+            }
+            drawn = !lines.is_empty();
+        } else if last_log.elapsed() >= LOG_INTERVAL {
+            last_log = Instant::now();
+            for (s, l) in lines {
+                let sent = s.bytes_sent();
+                if logged.insert(s.ident().clone(), sent) != Some(sent) {
+                    println!("[{}] {l}", s.ident().name());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coverage_merges() {
+        let mut c = Coverage::default();
+        c.add(10, 20);
+        c.add(30, 40);
+        c.add(5, 5); // empty
+        assert_eq!(c.0, [(10, 20), (30, 40)]);
+
+        c.add(20, 30); // touching both
+        assert_eq!(c.0, [(10, 40)]);
+        c.add(0, 5);
+        c.add(15, 25); // inside
+        assert_eq!(c.0, [(0, 5), (10, 40)]);
+        assert_eq!(c.covered(), 35);
+        assert!(!c.is_complete(40));
+
+        c.add(3, 12);
+        assert!(c.is_complete(40));
+        assert!(!c.is_complete(41));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use smol::lock::RwLock;
+
+use crate::{
+    device::{
+        UsbClient,
+        progress::{FileProgress, Outcome, Title, Transfer, clear_line},
+        transport::DeviceIdent,
+    },
+    history::{self, Record},
+};
+
+/// Live per-device state - written by the session, read by whoever's reporting
+pub struct SessionStats {
+    ident: DeviceIdent,
+    protocol: Mutex<Option<UsbClient>>, // only known after detection
+    bytes_sent: AtomicU64,
+    files: Mutex<Files>,             // std mutexes - never held across an await
+    transfers: Mutex<Vec<Transfer>>, // finished with, in order
+}
+
+#[derive(Default)]
+struct Files {
+    current: Option<PathBuf>,
+    progress: HashMap<PathBuf, FileProgress>, // every file touched and not finished with yet
+}
+
+/// Every live session, keyed by the device it's serving
+#[derive(Clone, Default)]
+pub struct SessionRegistry(Arc<RwLock<HashMap<DeviceIdent, Arc<SessionStats>>>>);
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    format!("{n:.1} {}", UNITS[unit])
+}
+
+/// `1h02m`, `3m20s`, `45s`
+pub fn human_duration(d: Duration) -> String {
+    let s = d.as_secs();
+    match (s / 3600, s / 60 % 60, s % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, _) => format!("{h}h{m:02}m"),
+    }
+}
+
+impl SessionStats {
+    pub fn new(ident: DeviceIdent) -> Self {
+        Self {
+            ident,
+            protocol: Mutex::new(None),
+            bytes_sent: AtomicU64::new(0),
+            files: Mutex::new(Files::default()),
+            transfers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn ident(&self) -> &DeviceIdent {
+        &self.ident
+    }
+
+    pub fn set_protocol(&self, client: UsbClient) {
+        *self.protocol.lock().unwrap() = Some(client); // set again if a detected sphaira turns out to be tinfoil
+    }
+
+    pub fn protocol(&self) -> Option<UsbClient> {
+        *self.protocol.lock().unwrap()
+    }
+
+    /// also moves the current file's progress along
+    pub fn add_sent(&self, n: u64) {
+        self.bytes_sent.fetch_add(n, Ordering::Relaxed);
+
+        let mut files = self.files.lock().unwrap();
+        let Some(path) = files.current.clone() else {
+            return;
+        };
+        let Some(p) = files.progress.get_mut(&path) else {
+            return;
+        };
+
+        p.advance(n);
+        if p.is_complete() {
+            let p = files.progress.remove(&path).unwrap();
+            drop(files);
+            self.record(p.finish(Outcome::Completed));
+        }
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// what's sent next is `path` (`size` bytes long) from `start` on
+    /// true if this is a different file to last time - the last one's abandoned if it was part way through
+    pub fn start_range(&self, path: &Path, size: u64, title: Option<Title>, start: u64) -> bool {
+        let mut files = self.files.lock().unwrap();
+        files
+            .progress
+            .entry(path.to_path_buf())
+            .or_insert_with(|| FileProgress::new(path, size, title))
+            .seek(start);
+
+        if files.current.as_deref() == Some(path) {
+            return false;
+        }
+
+        let previous = files.current.replace(path.to_path_buf());
+        let abandoned = previous
+            .filter(|p| files.progress.get(p).is_some_and(|p| p.is_underway()))
+            .and_then(|p| files.progress.remove(&p));
+        drop(files);
+
+        if let Some(p) = abandoned {
+            self.record(p.finish(Outcome::Abandoned));
+        }
+        true
+    }
+
+    /// session's over - anything part way through was abandoned
+    pub fn end(&self) {
+        let underway = {
+            let mut files = self.files.lock().unwrap();
+            files.current = None;
+            let (underway, rest) = files
+                .progress
+                .drain()
+                .partition::<Vec<_>, _>(|(_, p)| p.is_underway());
+            files.progress.extend(rest);
+            underway
+        };
+
+        for (_, p) in underway {
+            self.record(p.finish(Outcome::Abandoned));
+        }
+    }
+
+    fn record(&self, transfer: Transfer) {
+        clear_line();
+        println!("[{}] {transfer}", self.ident.name());
+        if let Err(e) = history::append(&Record::new(&self.ident.name(), &transfer)) {
+            println!(
+                "[{}] failed to write install history: {e}",
+                self.ident.name()
+            );
+        }
+        self.transfers.lock().unwrap().push(transfer);
+    }
+
+    /// completed and abandoned files, oldest first
+    pub fn transfers(&self) -> Vec<Transfer> {
+        self.transfers.lock().unwrap().clone()
+    }
+
+    /// None unless something's been sent recently
+    pub fn progress_line(&self) -> Option<String> {
+        let files = self.files.lock().unwrap();
+        files
+            .progress
+            .get(files.current.as_ref()?)
+            .filter(|p| p.is_active())
+            .map(|p| p.line())
+    }
+
+    pub fn current_file(&self) -> Option<PathBuf> {
+        self.files.lock().unwrap().current.clone()
+    }
+
+    pub fn summary(&self) -> String {
+        let protocol = self
+            .protocol()
+            .map_or("detecting".to_string(), |p| p.to_string());
+        let file = self
+            .current_file()
+            .and_then(|f| f.file_name().map(|f| f.to_string_lossy().to_string()))
+            .unwrap_or("idle".to_string());
+        let transfers = self.transfers();
+        let completed = transfers
+            .iter()
+            .filter(|t| t.outcome == Outcome::Completed)
+            .count();
+        format!(
+            "[{}] {protocol} - {} sent - {completed} completed, {} abandoned - {file}",
+            self.ident.name(),
+            human_bytes(self.bytes_sent()),
+            transfers.len() - completed
+        )
+    }
+}
+
+impl SessionRegistry {
+    pub async fn insert(&self, stats: Arc<SessionStats>) {
+        self.0.write().await.insert(stats.ident().clone(), stats);
+    }
+
+    pub async fn remove(&self, ident: &DeviceIdent) -> Option<Arc<SessionStats>> {
+        self.0.write().await.remove(ident)
+    }
+
+    pub async fn sessions(&self) -> Vec<Arc<SessionStats>> {
+        self.0.read().await.values().cloned().collect()
+    }
+
+    pub async fn report(&self) {
+        let sessions = self.0.read().await;
+        println!("{} switch(es) connected", sessions.len());
+        for stats in sessions.values() {
+            println!("  {}", stats.summary());
+        }
+    }
+}
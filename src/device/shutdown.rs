@@ -0,0 +1,38 @@
+use std::{
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use smol::channel::{Receiver, Sender, unbounded};
+
+/*
+Graceful shutdown - the first ctrl-c asks every session to stop at its next safe point, a second one forces it
+Safe points: waiting on the console for a command, or between chunks of a range once what's queued has gone out
+Nothing's ever sent on the channel - closing it wakes every waiter at once
+*/
+
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10); // sessions still going after this are cut off
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static STOP: LazyLock<(Sender<()>, Receiver<()>)> = LazyLock::new(unbounded);
+
+/// true the first time - after that it's up to the caller to force it
+pub fn request() -> bool {
+    let first = !STOPPING.swap(true, Ordering::Relaxed);
+    if first {
+        STOP.0.close();
+    }
+    first
+}
+
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// resolves once shutdown's been requested
+pub async fn stopped() {
+    let _ = STOP.1.recv().await;
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    task::{Context, Poll, ready},
+};
+
+use futures_io::AsyncRead;
+use smol::{io::Cursor, lock::OnceCell, unblock};
+
+mod portable;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+// both have open_file (blocking) + read_block
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+use portable as backend;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use uring as backend;
+
+use crate::{
+    device::{CHUNK_SIZE, FILE_CACHE_N},
+    game::Game,
+};
+
+/*
+Disk side of every transfer - shared by all sessions, so two switches pulling the same title only read it once
+Files are read in CHUNK_SIZE blocks (aligned to CHUNK_SIZE), the last FILE_CACHE_N of which stay in memory
+*/
+
+const HANDLE_POOL_N: usize = 16; // open files kept around between range requests
+
+pub type GameReader = Box<dyn AsyncRead + Send + Unpin>;
+
+type Block = Arc<Vec<u8>>; // short (or empty) at the end of a file
+type BlockKey = (PathBuf, u64); // (file, offset)
+type BlockFuture = Pin<Box<dyn Future<Output = io::Result<Block>> + Send>>;
+
+/// Tiny least-recently-used map - n is small, so eviction just scans
+struct Lru<K, V> {
+    cap: usize,
+    tick: u64,
+    map: HashMap<K, (V, u64)>,
+}
+
+struct Storage {
+    handles: Mutex<Lru<PathBuf, Arc<File>>>,
+    // cell per block, so concurrent requests for the same block wait on one read instead of racing to disk
+    blocks: Mutex<Lru<BlockKey, Arc<OnceCell<Block>>>>,
+}
+
+// std mutexes - never held across an await
+static STORAGE: LazyLock<Storage> = LazyLock::new(|| Storage {
+    handles: Mutex::new(Lru::new(HANDLE_POOL_N)),
+    blocks: Mutex::new(Lru::new(FILE_CACHE_N)),
+});
+
+/// AsyncRead over [start, end) of a file, served out of the block cache
+pub struct RangeReader {
+    path: PathBuf,
+    pos: u64,
+    end: u64,
+    block: Option<(u64, Block)>, // (offset, data) of the block pos is in
+    pending: Option<BlockFuture>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Lru<K, V> {
+    fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            tick: 0,
+            map: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, k: &K) -> Option<V> {
+        self.tick += 1;
+        let (v, used) = self.map.get_mut(k)?;
+        *used = self.tick;
+        Some(v.clone())
+    }
+
+    fn insert(&mut self, k: K, v: V) {
+        self.tick += 1;
+        self.map.insert(k, (v, self.tick));
+
+        if self.map.len() > self.cap
+            && let Some(oldest) = self
+                .map
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| k.clone())
+        {
+            self.map.remove(&oldest);
+        }
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&K) -> bool) {
+        self.map.retain(|k, _| f(k));
+    }
+
+    fn get_or_insert_with(&mut self, k: K, f: impl FnOnce() -> V) -> V {
+        if let Some(v) = self.get(&k) {
+            return v;
+        }
+        let v = f();
+        self.insert(k, v.clone());
+        v
+    }
+}
+
+async fn handle(path: &Path) -> io::Result<Arc<File>> {
+    if let Some(f) = STORAGE.handles.lock().unwrap().get(&path.to_path_buf()) {
+        return Ok(f);
+    }
+
+    let owned = path.to_path_buf();
+    let f = Arc::new(unblock(move || backend::open_file(&owned)).await?);
+    STORAGE
+        .handles
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), f.clone());
+    Ok(f)
+}
+
+async fn block(path: PathBuf, offset: u64) -> io::Result<Block> {
+    let cell = STORAGE
+        .blocks
+        .lock()
+        .unwrap()
+        .get_or_insert_with((path.clone(), offset), Default::default);
+
+    // a failed read leaves the cell empty, so the next request tries again
+    cell.get_or_try_init(|| async {
+        let f = handle(&path).await?;
+        backend::read_block(f, offset).await.map(Arc::new)
+    })
+    .await
+    .cloned()
+}
+
+/// drops everything kept for a file that's changed or gone - stale blocks would be served as the new file, and a
+/// pooled handle keeps the old one (and a deleted file's disk space) around
+/// reads already underway finish on what they had
+pub fn forget(path: &Path) {
+    STORAGE.handles.lock().unwrap().retain(|p| p != path);
+    STORAGE.blocks.lock().unwrap().retain(|(p, _)| p != path);
+}
+
+/// opens up front so a missing file is reported before anything's been sent
+async fn open_range(path: &Path, start: u64, end: u64) -> io::Result<RangeReader> {
+    handle(path).await?;
+    Ok(RangeReader {
+        path: path.to_path_buf(),
+        pos: start,
+        end,
+        block: None,
+        pending: None,
+    })
+}
+
+/// like open_range, but header probes are answered from the head kept at scan time
+pub async fn open_game_range(game: &Game, start: u64, end: u64) -> io::Result<GameReader> {
+    open_file_range(game.path(), game, start, end).await
+}
+
+/// `path` is one of `game`'s files - the head's only used if it was read from that one
+pub async fn open_file_range(
+    path: &Path,
+    game: &Game,
+    start: u64,
+    end: u64,
+) -> io::Result<GameReader> {
+    match game.head(start, end).filter(|_| game.path() == path) {
+        Some(head) => Ok(Box::new(Cursor::new(head.to_vec()))),
+        None => Ok(Box::new(open_range(path, start, end).await?)),
+    }
+}
+
+impl AsyncRead for RangeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos >= this.end || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let offset = this.pos - this.pos % CHUNK_SIZE;
+            if let Some((o, data)) = &this.block
+                && *o == offset
+            {
+                let start = (this.pos - offset) as usize;
+                let n = data
+                    .len()
+                    .saturating_sub(start)
+                    .min(buf.len())
+                    .min((this.end - this.pos) as usize);
+
+                // past the end of the file
+                if n == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                buf[..n].copy_from_slice(&data[start..start + n]);
+                this.pos += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+
+            let pending = this
+                .pending
+                .get_or_insert_with(|| Box::pin(block(this.path.clone(), offset)));
+            let res = ready!(pending.as_mut().poll(cx));
+            this.pending = None;
+            this.block = Some((offset, res?));
+        }
+    }
+}
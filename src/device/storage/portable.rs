@@ -0,0 +1,45 @@
+use std::{fs::File, io, path::Path, sync::Arc};
+
+use smol::unblock;
+
+use crate::device::CHUNK_SIZE;
+
+// plain blocking reads on smol's thread pool - works everywhere
+
+pub fn open_file(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// blocking - positional, so one handle can serve any number of readers
+fn read_block_blocking(file: &File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut n = 0;
+    while n < buf.len() {
+        match read_at(file, &mut buf[n..], offset + n as u64) {
+            Ok(0) => break,
+            Ok(r) => n += r,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if n < buf.len() {
+        buf.truncate(n);
+        buf.shrink_to_fit();
+    }
+    Ok(buf)
+}
+
+pub async fn read_block(file: Arc<File>, offset: u64) -> io::Result<Vec<u8>> {
+    unblock(move || read_block_blocking(&file, offset)).await
+}
@@ -0,0 +1,214 @@
+use std::{
+    alloc::{self, Layout},
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    ptr::NonNull,
+    slice,
+    sync::{Arc, LazyLock},
+    thread,
+};
+
+use io_uring::{IoUring, opcode, types};
+use smol::channel::{self, Receiver, Sender};
+
+use crate::device::{CHUNK_SIZE, storage::portable};
+
+/*
+io_uring + O_DIRECT - one thread owns the ring and keeps up to RING_N block reads in flight
+O_DIRECT skips the page cache, so streaming a multi-gb archive doesn't push everything else out of it - the block cache is all the caching there is
+Blocks are CHUNK_SIZE long at CHUNK_SIZE offsets, so they're always aligned - only the buffers need care
+*/
+
+const RING_N: u32 = 8;
+const ALIGN_N: usize = 4096; // O_DIRECT wants buffer, offset and length aligned to the logical block size - 4k covers the usual ones
+
+struct Request {
+    file: Arc<File>,
+    offset: u64,
+    reply: Sender<io::Result<Vec<u8>>>,
+}
+
+/// CHUNK_SIZE bytes aligned to ALIGN_N - Vec can't promise alignment
+struct AlignedBuf(NonNull<u8>);
+
+struct InFlight {
+    req: Request,
+    buf: AlignedBuf,
+    filled: usize,
+}
+
+// None -> no io_uring here (old kernel, seccomp'd container...) and everything goes down the portable path
+static RING: LazyLock<Option<Sender<Request>>> = LazyLock::new(|| match IoUring::new(RING_N) {
+    Ok(ring) => {
+        let (tx, rx) = channel::unbounded();
+        thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || run(ring, rx))
+            .ok()?;
+        Some(tx)
+    }
+    Err(e) => {
+        eprintln!("io_uring unavailable, falling back to blocking reads: {e}");
+        None
+    }
+});
+
+impl AlignedBuf {
+    const LAYOUT: Layout = match Layout::from_size_align(CHUNK_SIZE as usize, ALIGN_N) {
+        Ok(l) => l,
+        Err(_) => panic!("bad buffer layout"),
+    };
+
+    fn new() -> Self {
+        // SAFETY: LAYOUT has a non-zero size
+        let ptr = unsafe { alloc::alloc_zeroed(Self::LAYOUT) };
+        Self(NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(Self::LAYOUT)))
+    }
+
+    fn filled(&self, n: usize) -> &[u8] {
+        // SAFETY: n <= CHUNK_SIZE, and it's all initialised (zeroed on alloc)
+        unsafe { slice::from_raw_parts(self.0.as_ptr(), n) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: allocated in new with the same layout
+        unsafe { alloc::dealloc(self.0.as_ptr(), Self::LAYOUT) }
+    }
+}
+
+pub fn open_file(path: &Path) -> io::Result<File> {
+    if RING.is_none() {
+        return portable::open_file(path);
+    }
+
+    // tmpfs and friends don't do O_DIRECT - aligned reads still work without it
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => File::open(path),
+        res => res,
+    }
+}
+
+pub async fn read_block(file: Arc<File>, offset: u64) -> io::Result<Vec<u8>> {
+    let Some(ring) = RING.as_ref() else {
+        return portable::read_block(file, offset).await;
+    };
+
+    let gone = || io::Error::other("io_uring thread died");
+    let (reply, rx) = channel::bounded(1);
+    ring.send(Request {
+        file,
+        offset,
+        reply,
+    })
+    .await
+    .map_err(|_| gone())?;
+    rx.recv().await.map_err(|_| gone())?
+}
+
+/// reads the rest of the block - buf and file stay in `in_flight` until its completion is reaped
+fn submit_read(ring: &mut IoUring, id: u64, f: &InFlight) {
+    let entry = opcode::Read::new(
+        types::Fd(f.req.file.as_raw_fd()),
+        // SAFETY: filled < CHUNK_SIZE, so still inside the buffer
+        unsafe { f.buf.0.as_ptr().add(f.filled) },
+        (CHUNK_SIZE as usize - f.filled) as u32,
+    )
+    .offset(f.req.offset + f.filled as u64)
+    .build()
+    .user_data(id);
+
+    // SAFETY: see above - and the queue can't be full, in_flight never goes over RING_N
+    let _ = unsafe { ring.submission().push(&entry) };
+}
+
+fn run(mut ring: IoUring, rx: Receiver<Request>) {
+    let mut in_flight = HashMap::<u64, InFlight>::new();
+    let mut free = Vec::<AlignedBuf>::new();
+    let mut next_id = 0u64;
+
+    loop {
+        // nothing to reap -> sleep until there's work
+        // (while waiting on completions new requests queue up in the channel, they're picked up on the next one)
+        let mut incoming = Vec::new();
+        if in_flight.is_empty() {
+            match rx.recv_blocking() {
+                Ok(req) => incoming.push(req),
+                Err(_) => return,
+            }
+        }
+        while in_flight.len() + incoming.len() < RING_N as usize
+            && let Ok(req) = rx.try_recv()
+        {
+            incoming.push(req);
+        }
+
+        for req in incoming {
+            let f = InFlight {
+                req,
+                buf: free.pop().unwrap_or_else(AlignedBuf::new),
+                filled: 0,
+            };
+            submit_read(&mut ring, next_id, &f);
+            in_flight.insert(next_id, f);
+            next_id += 1;
+        }
+
+        if let Err(e) = ring.submit_and_wait(1) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            // ring's unusable - fail everything waiting rather than hang it
+            for (_, f) in in_flight.drain() {
+                let _ = f
+                    .req
+                    .reply
+                    .try_send(Err(io::Error::new(e.kind(), e.to_string())));
+            }
+            return;
+        }
+
+        let done = ring
+            .completion()
+            .map(|c| (c.user_data(), c.result()))
+            .collect::<Vec<_>>();
+
+        for (id, res) in done {
+            let Some(mut f) = in_flight.remove(&id) else {
+                continue;
+            };
+
+            let res = match res {
+                r if r == -libc::EINTR || r == -libc::EAGAIN => None,
+                r if r < 0 => Some(Err(io::Error::from_raw_os_error(-r))),
+                0 => Some(Ok(())),
+                r => {
+                    f.filled += r as usize;
+                    // short + unaligned -> hit the end of the file
+                    let more = f.filled < CHUNK_SIZE as usize && f.filled % ALIGN_N == 0;
+                    (!more).then_some(Ok(()))
+                }
+            };
+
+            match res {
+                None => {
+                    submit_read(&mut ring, id, &f);
+                    in_flight.insert(id, f);
+                }
+                Some(res) => {
+                    let res = res.map(|_| f.buf.filled(f.filled).to_vec());
+                    let _ = f.req.reply.try_send(res); // requester might've given up
+                    free.push(f.buf);
+                }
+            }
+        }
+    }
+}
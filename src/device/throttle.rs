@@ -0,0 +1,163 @@
+use std::{
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/*
+Rate limits - global (split between every session) and per device
+Data goes out in SLICE_N slices, each waits for whichever limit is further behind
+The first FREE_N of every range jumps the queue (header probes shouldn't wait behind someone's 30gb xci) - it still counts,
+and only gets to run up to FREE_DEBT ahead of the rate, so a client reading in small ranges is held to the limit as well
+With n sessions past that point each gets 1/n of the global rate, so one big download can't hog it
+Every byte is charged to one shared bucket as well, so sessions that haven't joined the split (probes) can't push
+the total past the global rate
+*/
+
+pub const SLICE_N: usize = 0x100000; // 1mb - granularity limits are enforced at
+pub const FREE_N: u64 = 0x100000; // start of a range that jumps the queue
+const BURST: Duration = Duration::from_millis(200); // how far ahead of the rate an idle session may get
+const FREE_DEBT: Duration = Duration::from_secs(1); // how far behind the rate the free part may leave a session
+
+// bytes/s, 0 -> unlimited
+static GLOBAL_LIMIT: AtomicU64 = AtomicU64::new(0);
+static DEVICE_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+static ACTIVE_N: AtomicUsize = AtomicUsize::new(0); // sessions currently sharing the global rate
+static GLOBAL: LazyLock<Mutex<TokenBucket>> = LazyLock::new(Default::default); // all sessions, joined or not
+
+struct TokenBucket {
+    tokens: f64, // negative -> in debt, has to wait it off
+    last: Instant,
+}
+
+/// Per-session limiter state
+#[derive(Default)]
+pub struct Throttle {
+    device: TokenBucket,
+    share: TokenBucket, // this session's slice of the global rate
+}
+
+/// Held while a session is in the bulk of a transfer - counts it in the global split
+pub struct Active(());
+
+pub fn set_limits(global: Option<u64>, device: Option<u64>) {
+    GLOBAL_LIMIT.store(global.unwrap_or(0), Ordering::Relaxed);
+    DEVICE_LIMIT.store(device.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// bytes per second - `500k`, `20M`, `1.5G` (binary units), trailing `B` or `/s` are fine
+pub fn parse_rate(s: &str) -> Option<u64> {
+    let s = s.trim().trim_end_matches("/s").trim_end_matches(['B', 'b']);
+    let (n, unit) = match s.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, ' '),
+    };
+    let mult = match unit {
+        ' ' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return None,
+    };
+    let n = n.trim().parse::<f64>().ok().filter(|n| *n > 0.0)?;
+    Some((n * mult as f64) as u64)
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self {
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl TokenBucket {
+    /// takes n tokens (going into debt if need be), returns how long until the debt's paid off
+    fn take(&mut self, n: u64, rate: u64) -> Duration {
+        let now = Instant::now();
+        let rate = rate as f64;
+        let burst = (rate * BURST.as_secs_f64()).max(SLICE_N as f64);
+
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(burst);
+        self.last = now;
+        self.tokens -= n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+impl Throttle {
+    /// counts n bytes against the limits, returns how long to hold off before sending them
+    /// `free` bytes only wait off whatever debt's past FREE_DEBT
+    pub fn take(&mut self, n: u64, free: bool) -> Duration {
+        let mut wait = Duration::ZERO;
+
+        let device = DEVICE_LIMIT.load(Ordering::Relaxed);
+        if device > 0 {
+            wait = wait.max(self.device.take(n, device));
+        }
+
+        let global = GLOBAL_LIMIT.load(Ordering::Relaxed);
+        if global > 0 {
+            let share = global / ACTIVE_N.load(Ordering::Relaxed).max(1) as u64;
+            wait = wait.max(self.share.take(n, share.max(1)));
+            wait = wait.max(GLOBAL.lock().unwrap().take(n, global));
+        }
+
+        match free {
+            true => wait.saturating_sub(FREE_DEBT),
+            false => wait,
+        }
+    }
+}
+
+impl Active {
+    pub fn join() -> Self {
+        ACTIVE_N.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE_N.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("500"), Some(500));
+        assert_eq!(parse_rate("500k"), Some(500 << 10));
+        assert_eq!(parse_rate("20MB/s"), Some(20 << 20));
+        assert_eq!(parse_rate(" 1.5G "), Some(3 << 29));
+        assert_eq!(parse_rate("0"), None);
+        assert_eq!(parse_rate("-5M"), None);
+        assert_eq!(parse_rate("5T"), None);
+        assert_eq!(parse_rate(""), None);
+    }
+
+    #[test]
+    fn global_covers_every_session() {
+        // neither's joined the split (a couple of probes) - still only the one global rate between them
+        set_limits(Some(SLICE_N as u64), None);
+        let (mut a, mut b) = (Throttle::default(), Throttle::default());
+        let first = a.take(SLICE_N as u64, false);
+        let second = b.take(SLICE_N as u64, false);
+        set_limits(None, None);
+
+        assert!(first.as_secs_f64() > 0.9, "{first:?}");
+        assert!(second.as_secs_f64() > 1.9, "{second:?}");
+    }
+}
@@ -0,0 +1,92 @@
+pub mod pipe;
+pub mod usb;
+
+use std::{fmt::Display, io, pin::Pin};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+// dyn halves so hosts don't need to be generic over the transport
+pub type TransportRx = dyn AsyncRead + Send + Sync + Unpin;
+pub type TransportTx = dyn AsyncWrite + Send + Sync + Unpin;
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+const DEFAULT_PACKET_N: usize = 512; // usb 2 high speed bulk
+
+/// Who's on the other end - usb devices fill this from their descriptors, pipes make one up
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceIdent {
+    pub vendor: u16,
+    pub product: u16,
+    pub bus: String,
+    pub address: u8,
+    pub ports: Vec<u8>, // hub ports from the root down - stable across replugs, unlike address
+    pub serial: Option<String>,
+}
+
+impl Display for DeviceIdent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x}@{}-{}",
+            self.vendor, self.product, self.bus, self.address
+        )?;
+        write!(f, " port {}", self.port_path())?;
+        if let Some(serial) = &self.serial {
+            write!(f, " ({serial})")?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceIdent {
+    /// short tag for logs - serial if the device has one
+    pub fn name(&self) -> String {
+        match &self.serial {
+            Some(serial) => serial.clone(),
+            None => format!("{}-{}", self.bus, self.address),
+        }
+    }
+
+    /// where it's plugged in, e.g. `001-2.1` - what `--port` matches against
+    pub fn port_path(&self) -> String {
+        let ports = self
+            .ports
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        format!("{}-{ports}", self.bus)
+    }
+}
+
+/// Byte pipe to a console - hosts only ever talk through this
+/// read/write/flush come from the Async{Read,Write}Ext traits on the halves
+pub trait SwitchTransport: Send + Sync {
+    fn rx(&mut self) -> &mut TransportRx;
+    fn tx(&mut self) -> &mut TransportTx;
+    fn ident(&self) -> &DeviceIdent;
+
+    /// both halves at once - to listen for the console while writing to it
+    fn split(&mut self) -> (&mut TransportRx, &mut TransportTx);
+
+    /// of the out endpoint - full chunks are kept a multiple of this
+    fn max_packet_size(&self) -> usize {
+        DEFAULT_PACKET_N
+    }
+
+    /// hand over whatever's buffered without waiting for it to go out, flush waits
+    fn submit(&mut self) {}
+
+    /// submit, ending in a short packet - a zero length one if what's buffered fills its last packet
+    fn submit_end(&mut self) {}
+
+    /// drops everything written that the console hasn't taken yet, so the next write is the first thing it reads
+    fn abort_tx(&mut self) -> TransportFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// cheap round trip to check the console is still there, used once the link goes quiet
+    fn keepalive(&mut self) -> TransportFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    task::{Context, Poll, Waker},
+};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::device::transport::{
+    DeviceIdent, SwitchTransport, TransportFuture, TransportRx, TransportTx,
+};
+
+const PIPE_BUFF_N: usize = 0x10000; // small on purpose, so backpressure actually gets exercised
+
+static PIPE_N: AtomicU8 = AtomicU8::new(0); // only so each pipe gets a unique ident
+
+/// One direction of the duplex - written bytes sit here until the other end reads them, like a queued usb transfer
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool, // either end's gone
+    reader: Option<Waker>,
+    writer: Option<Waker>, // waiting on room, or on a flush
+}
+
+struct PipeReader(Arc<Mutex<Buffer>>);
+struct PipeWriter(Arc<Mutex<Buffer>>);
+
+/// One end of an in-memory duplex - same role as a usb device, minus the usb
+pub struct PipeTransport {
+    rx: PipeReader,
+    tx: PipeWriter,
+    ident: DeviceIdent,
+    quiet: Arc<AtomicBool>, // shared by both ends - set, keepalives fail like they would for a crashed switch
+}
+
+fn pipe() -> (PipeReader, PipeWriter) {
+    let buffer = Arc::new(Mutex::new(Buffer::default()));
+    (PipeReader(buffer.clone()), PipeWriter(buffer))
+}
+
+/// (host end, console end) - whatever one end writes, the other reads
+pub fn duplex() -> (PipeTransport, PipeTransport) {
+    let (host_rx, console_tx) = pipe();
+    let (console_rx, host_tx) = pipe();
+
+    let quiet = Arc::new(AtomicBool::new(false));
+    let ident = DeviceIdent {
+        vendor: 0,
+        product: 0,
+        bus: "pipe".to_string(),
+        address: PIPE_N.fetch_add(1, Ordering::Relaxed),
+        ports: vec![],
+        serial: None,
+    };
+
+    (
+        PipeTransport {
+            rx: host_rx,
+            tx: host_tx,
+            ident: ident.clone(),
+            quiet: quiet.clone(),
+        },
+        PipeTransport {
+            rx: console_rx,
+            tx: console_tx,
+            ident,
+            quiet,
+        },
+    )
+}
+
+impl PipeTransport {
+    /// stops answering keepalives, from either end
+    #[cfg(test)]
+    pub fn go_quiet(&self) {
+        self.quiet.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Buffer {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake_reader();
+        self.wake_writer();
+    }
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.data.is_empty() && !buf.is_empty() && !pipe.closed {
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = pipe.data.read(buf)?;
+        pipe.wake_writer();
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.0.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        let n = buf.len().min(PIPE_BUFF_N - pipe.data.len());
+        if n == 0 && !buf.is_empty() {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        pipe.data.extend(&buf[..n]);
+        pipe.wake_reader();
+        Poll::Ready(Ok(n))
+    }
+
+    /// waits for the other end to read it all - same as a usb transfer completing
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.0.lock().unwrap();
+        match (pipe.data.is_empty(), pipe.closed) {
+            (true, _) => Poll::Ready(Ok(())),
+            (false, true) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            (false, false) => {
+                pipe.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().close();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().close();
+    }
+}
+
+impl SwitchTransport for PipeTransport {
+    fn rx(&mut self) -> &mut TransportRx {
+        &mut self.rx
+    }
+
+    fn tx(&mut self) -> &mut TransportTx {
+        &mut self.tx
+    }
+
+    fn ident(&self) -> &DeviceIdent {
+        &self.ident
+    }
+
+    fn split(&mut self) -> (&mut TransportRx, &mut TransportTx) {
+        (&mut self.rx, &mut self.tx)
+    }
+
+    /// whatever the other end hasn't read yet is gone
+    fn abort_tx(&mut self) -> TransportFuture<'_> {
+        let mut pipe = self.tx.0.lock().unwrap();
+        pipe.data.clear();
+        pipe.wake_writer();
+        Box::pin(async { Ok(()) })
+    }
+
+    fn keepalive(&mut self) -> TransportFuture<'_> {
+        let quiet = self.quiet.load(Ordering::Relaxed);
+        Box::pin(async move {
+            match quiet {
+                true => Err(io::ErrorKind::TimedOut.into()),
+                false => Ok(()),
+            }
+        })
+    }
+}
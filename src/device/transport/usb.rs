@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use nusb::{
+    Device, DeviceInfo, Endpoint, Interface,
+    io::{EndpointRead, EndpointWrite},
+    transfer::{
+        Bulk, BulkOrInterrupt, ControlIn, ControlType, Direction, EndpointDirection, Out, Recipient,
+    },
+};
+use smol::Timer;
+
+use crate::device::{
+    RX_BUFF_N, TX_TRANSFER_N, TX_TRANSFERS_N,
+    interface::SwitchInitError,
+    transport::{DeviceIdent, SwitchTransport, TransportFuture, TransportRx, TransportTx},
+};
+
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(1);
+const GET_STATUS: u8 = 0x00;
+
+pub struct UsbTransport {
+    interface: Interface, // tinfoil's interface - there's only one really... (keeps the device open too)
+    rx: EndpointRead<Bulk>,
+    tx: Option<EndpointWrite<Bulk>>, // only None while an abort has the endpoint back
+    max_packet_size: usize,
+    ident: DeviceIdent,
+}
+
+impl From<&DeviceInfo> for DeviceIdent {
+    fn from(d_info: &DeviceInfo) -> Self {
+        Self {
+            vendor: d_info.vendor_id(),
+            product: d_info.product_id(),
+            bus: d_info.bus_id().to_string(),
+            address: d_info.device_address(),
+            ports: d_info.port_chain().to_vec(),
+            serial: d_info.serial_number().map(|s| s.to_string()),
+        }
+    }
+}
+
+fn writer(out_ep: Endpoint<Bulk, Out>) -> EndpointWrite<Bulk> {
+    EndpointWrite::new(out_ep, TX_TRANSFER_N).with_num_transfers(TX_TRANSFERS_N)
+}
+
+/// puts a writer back on the endpoint however abort_tx ends - dropped part way (timed out), tx would be left empty
+struct RestoreTx<'a> {
+    slot: &'a mut Option<EndpointWrite<Bulk>>,
+    out_ep: Option<Endpoint<Bulk, Out>>,
+}
+
+impl Drop for RestoreTx<'_> {
+    fn drop(&mut self) {
+        if let Some(out_ep) = self.out_ep.take() {
+            *self.slot = Some(writer(out_ep));
+        }
+    }
+}
+
+/// not every backend supports this (WinUSB is picky) - a failure here isn't worth giving up over
+async fn clear_halt<EpType: BulkOrInterrupt, Dir: EndpointDirection>(
+    ep: &mut Endpoint<EpType, Dir>,
+) {
+    if let Err(e) = ep.clear_halt().await {
+        eprintln!("Failed to clear halt on {ep:?}: {e:?}");
+    }
+}
+
+impl UsbTransport {
+    /// `recovering` clears any halt left over from a previous session before use
+    pub async fn open(
+        device: Device,
+        device_info: &DeviceInfo,
+        recovering: bool,
+    ) -> Result<Self, SwitchInitError> {
+        // following not important as tinfoil interface has one config anyways - these aren't supported on windows/WinUSB
+        // device.reset()?;
+        // let (device, device_info) = get_conn().await?;
+
+        // tinfoil's usb interface - one config, 2 interfaces but still try to be dynamic...
+        // device.set_configuration(1)?;
+
+        let interface = match device.claim_interface(0).await {
+            Ok(i) => i,
+            Err(_) => {
+                // for windows, interfaces might only be available after a small delay
+                Timer::after(Duration::from_millis(500)).await;
+                device.claim_interface(0).await?
+            }
+        }; // why are there 2 interfaces anyways...
+
+        let a_set = interface
+            .descriptors()
+            .find(|d| d.alternate_setting() == 0)
+            .ok_or(SwitchInitError::NoInterface)?;
+
+        // could've hardcoded addresses but future-proofing
+        let out_ep = a_set
+            .endpoints()
+            .find(|ep| ep.direction() == Direction::Out)
+            .ok_or(SwitchInitError::EpNotFound)?
+            .address();
+
+        let in_ep = a_set
+            .endpoints()
+            .find(|ep| ep.direction() == Direction::In)
+            .ok_or(SwitchInitError::EpNotFound)?
+            .address();
+
+        let mut out_ep = interface.endpoint::<Bulk, _>(out_ep)?;
+        let mut in_ep = interface.endpoint::<Bulk, _>(in_ep)?;
+        if recovering {
+            clear_halt(&mut out_ep).await;
+            clear_halt(&mut in_ep).await;
+        }
+
+        let max_packet_size = out_ep.max_packet_size();
+        let tx = writer(out_ep);
+        let rx = EndpointRead::new(in_ep, RX_BUFF_N);
+
+        Ok(Self {
+            interface,
+            rx,
+            tx: Some(tx),
+            max_packet_size,
+            ident: DeviceIdent::from(device_info),
+        })
+    }
+}
+
+impl SwitchTransport for UsbTransport {
+    fn rx(&mut self) -> &mut TransportRx {
+        &mut self.rx
+    }
+
+    fn tx(&mut self) -> &mut TransportTx {
+        self.tx.as_mut().expect("tx taken")
+    }
+
+    fn split(&mut self) -> (&mut TransportRx, &mut TransportTx) {
+        (&mut self.rx, self.tx.as_mut().expect("tx taken"))
+    }
+
+    fn ident(&self) -> &DeviceIdent {
+        &self.ident
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    fn submit(&mut self) {
+        if let Some(tx) = self.tx.as_mut() {
+            tx.submit();
+        }
+    }
+
+    fn submit_end(&mut self) {
+        if let Some(tx) = self.tx.as_mut() {
+            tx.submit_end();
+        }
+    }
+
+    fn abort_tx(&mut self) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let Some(tx) = self.tx.take() else {
+                return Ok(());
+            };
+
+            // unsubmitted data goes with the writer, queued transfers get cancelled and reaped
+            let mut restore = RestoreTx {
+                slot: &mut self.tx,
+                out_ep: Some(tx.into_inner()),
+            };
+            let out_ep = restore.out_ep.as_mut().unwrap();
+            out_ep.cancel_all();
+            while out_ep.pending() > 0 {
+                out_ep.next_complete().await;
+            }
+            Ok(())
+        })
+    }
+
+    fn keepalive(&mut self) -> TransportFuture<'_> {
+        // GET_STATUS on our interface - harmless, and the switch has to answer it
+        let status = ControlIn {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Interface,
+            request: GET_STATUS,
+            value: 0,
+            index: self.interface.interface_number() as u16,
+            length: 2,
+        };
+        Box::pin(async move {
+            self.interface.control_in(status, KEEPALIVE_TIMEOUT).await?;
+            Ok(())
+        })
+    }
+}
@@ -1,31 +1,182 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
 use futures_io::AsyncRead;
-use smol::io::{self, AsyncReadExt, AsyncWriteExt};
+use smol::{
+    channel::bounded,
+    future,
+    io::{self, AsyncReadExt},
+};
 
-use crate::device::{CHUNK_SIZE, SwitchCommError, SwitchHost};
+use crate::device::{
+    CHUNK_SIZE, SwitchCommError, SwitchHost,
+    session::human_bytes,
+    shutdown,
+    throttle::{Active, FREE_N, SLICE_N},
+};
 
 /*
 Look here to optimise file transfer speeds
+Disk reads run ahead of usb writes - while chunk N is on the wire, chunks N+1.. are being read
+Chunks are handed to the transport without waiting, so several bulk transfers are queued at once - only the end of a range is waited on
+Chunk size starts off based on the link speed and is retuned per session from measured throughput
 */
 
-#[derive(PartialEq)]
-pub enum ChunkStatus {
-    Remaining,
-    End,
+const DEFAULT_READ_AHEAD_N: usize = 2; // chunks read ahead of the one being written, 0 -> read then write
+
+const MIN_CHUNK_N: u64 = 0x100000; // 1mb
+const MAX_CHUNK_N: u64 = 0x1000000; // 16mb
+const SUPERSPEED_PACKET_N: usize = 1024; // usb 3 bulk endpoints, usb 2 is 512
+const CHUNK_TARGET: Duration = Duration::from_millis(250); // aim for one chunk on the wire about this long
+
+static READ_AHEAD_N: AtomicUsize = AtomicUsize::new(DEFAULT_READ_AHEAD_N);
+
+pub fn set_read_ahead(n: usize) {
+    READ_AHEAD_N.store(n, Ordering::Relaxed);
+}
+
+/// where a session starts, before there's anything measured
+pub fn initial_chunk_size(max_packet_size: usize) -> u64 {
+    if max_packet_size >= SUPERSPEED_PACKET_N {
+        MAX_CHUNK_N
+    } else {
+        CHUNK_SIZE
+    }
+}
+
+/// biggest power of 2 that goes out in ~CHUNK_TARGET at this rate
+/// powers of 2 >= 1mb are always a multiple of the packet size, so full chunks never end in a short packet
+fn tuned_chunk_size(sent: u64, elapsed: Duration) -> u64 {
+    let rate = sent as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let target = ((rate * CHUNK_TARGET.as_secs_f64()) as u64).clamp(MIN_CHUNK_N, MAX_CHUNK_N);
+    1 << (u64::BITS - 1 - target.leading_zeros())
 }
 
 pub trait SwitchHostWriterExt: SwitchHost {
-    async fn write_next_chunk<R: AsyncRead>(
+    /// chunk size is tuned up to this
+    const MAX_CHUNK_N: u64 = MAX_CHUNK_N;
+
+    /// sent before every chunk - tinfoil frames each one, sphaira doesn't
+    fn chunk_header(_len: u64) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// one chunk onto the wire, doesn't wait for it to go out
+    /// `sent` is how far into the range it starts - the start of a range isn't held back by the limits
+    async fn write_next_chunk(&mut self, chunk: &[u8], sent: u64) -> Result<(), SwitchCommError> {
+        let interface = self.get_interface_mut();
+        if let Some(header) = Self::chunk_header(chunk.len() as u64) {
+            interface.write_transfer(&header).await?;
+            interface.submit(); // short packet -> the console reads it on its own
+        }
+
+        for (i, slice) in chunk.chunks(SLICE_N).enumerate() {
+            let at = sent + (i * SLICE_N) as u64;
+            interface.pace(slice.len() as u64, at < FREE_N).await;
+            interface.write_transfer(slice).await?;
+            interface.get_stats().add_sent(slice.len() as u64);
+        }
+        interface.submit();
+        Ok(())
+    }
+
+    /// streams all of `reader` in chunks - the last one is short, which marks the end
+    async fn write_chunks<R: AsyncRead + Unpin>(
         &mut self,
-        reader: R,
-    ) -> Result<ChunkStatus, SwitchCommError> {
-        let n = io::copy(reader.take(CHUNK_SIZE), self.get_interface_mut().get_tx()).await?;
-        // don't need to flush as tx's internal buffer size == CHUNK_SIZE
-
-        if n < CHUNK_SIZE as u64 {
-            self.get_interface_mut().get_tx().flush().await?;
-            return Ok(ChunkStatus::End);
-        } else {
-            return Ok(ChunkStatus::Remaining);
+        mut reader: R,
+    ) -> Result<(), SwitchCommError> {
+        let depth = READ_AHEAD_N.load(Ordering::Relaxed);
+        let chunk_n = self.get_interface().chunk_size().min(Self::MAX_CHUNK_N);
+
+        // buffers go round in a circle: empty -> reader fills -> writer sends -> empty
+        let (empty_tx, empty_rx) = bounded::<Vec<u8>>(depth + 1);
+        let (full_tx, full_rx) = bounded::<io::Result<Vec<u8>>>(depth.max(1));
+        for _ in 0..=depth {
+            let _ = empty_tx.try_send(Vec::with_capacity(chunk_n as usize));
+        }
+
+        let read = async move {
+            while let Ok(mut buf) = empty_rx.recv().await {
+                let res = (&mut reader)
+                    .take(chunk_n)
+                    .read_to_end(&mut buf)
+                    .await
+                    .map(|_| buf);
+                let end = res.as_ref().is_ok_and(|b| (b.len() as u64) < chunk_n);
+
+                // writer gone -> it errored, nothing left to do
+                if full_tx.send(res).await.is_err() || end {
+                    break;
+                }
+            }
+        };
+
+        // moves full_rx in, so the reader sees the channel close as soon as this returns
+        let write = async move {
+            let started = Instant::now();
+            let mut sent = 0;
+            let mut active = None; // past the free part -> sharing the global limit
+            while let Ok(buf) = full_rx.recv().await {
+                let mut buf = buf?;
+                let last = (buf.len() as u64) < chunk_n;
+
+                // an empty chunk only goes out if it's the whole range, otherwise it'd be a stray frame
+                if !buf.is_empty() || sent == 0 {
+                    if sent + buf.len() as u64 > FREE_N {
+                        active.get_or_insert_with(Active::join);
+                    }
+                    self.write_next_chunk(&buf, sent).await?;
+                    sent += buf.len() as u64;
+                }
+
+                if last {
+                    // end of the range is the end of the message, see codec
+                    let interface = self.get_interface_mut();
+                    interface.submit_end();
+                    interface.flush_transfer().await?; // waits on everything queued so far
+                    break;
+                }
+                // shutting down -> this chunk's the last, once it's actually gone out
+                if shutdown::is_stopping() {
+                    self.get_interface_mut().flush_transfer().await?;
+                    return Err(SwitchCommError::ShuttingDown);
+                }
+                buf.clear();
+                let _ = empty_tx.send(buf).await;
+            }
+
+            // short ranges (header probes) say more about latency than throughput
+            if sent >= 2 * chunk_n {
+                let interface = self.get_interface_mut();
+                let tuned = tuned_chunk_size(sent, started.elapsed()).min(Self::MAX_CHUNK_N);
+                if tuned != interface.chunk_size() {
+                    interface.set_chunk_size(tuned);
+                    interface.log(format_args!("chunk size -> {}", human_bytes(tuned)));
+                }
+            }
+            Ok(())
+        };
+
+        future::zip(read, write).await.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_sizes() {
+        let second = Duration::from_secs(1);
+        assert_eq!(tuned_chunk_size(0, second), MIN_CHUNK_N);
+        assert_eq!(tuned_chunk_size(1 << 30, Duration::ZERO), MAX_CHUNK_N);
+        // 40mb/s -> 10mb in CHUNK_TARGET -> 8mb
+        assert_eq!(tuned_chunk_size(40 << 20, second), 8 << 20);
+        assert_eq!(tuned_chunk_size(1 << 40, second), MAX_CHUNK_N);
+        for rate in [3 << 20, 30 << 20, 300 << 20] {
+            assert!(tuned_chunk_size(rate, second).is_power_of_two());
         }
     }
 }
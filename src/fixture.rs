@@ -0,0 +1,42 @@
+/*
+Scratch libraries for tests - a temp dir of archives, removed once the test's done with it
+Ids come from the file names, so nothing in them needs to parse
+*/
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+pub struct Library(PathBuf);
+
+impl Library {
+    /// `test` keeps tests running side by side out of each other's way
+    pub fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("frhop-{test}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// n bytes - no two files alike and no repeats within one, so a misplaced range can't pass
+    pub fn write(&self, rel: &str, n: usize) -> PathBuf {
+        let p = self.0.join(rel);
+        fs::create_dir_all(p.parent().unwrap()).unwrap();
+        let bytes = (0..n)
+            .map(|i| (i ^ (i >> 8) ^ (i >> 16) ^ rel.len()) as u8)
+            .collect::<Vec<_>>();
+        fs::write(&p, bytes).unwrap();
+        p
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
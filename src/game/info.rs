@@ -3,7 +3,10 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::game::{GameError, nsp::Nsp};
+use crate::game::{
+    GameError,
+    nsp::{Nsp, PFS0Header},
+};
 
 // kept separate to make serialisation easy
 // rename macro used to enforce that name is FIXED
@@ -28,7 +31,8 @@ struct Extractor {
 }
 
 impl GameInfo {
-    pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self, GameError> {
+    /// `head` is the start of the file, only parsed if the name doesn't say what it is
+    pub fn try_new<P: AsRef<Path>>(path: P, head: &[u8]) -> Result<Self, GameError> {
         let p = path.as_ref().to_path_buf();
 
         let f_base = p
@@ -41,7 +45,7 @@ impl GameInfo {
             println!(
                 "Warning; failed to extract info from name [{f_base}]: {e:?} - trying to extract from binary..."
             );
-            ex = Extractor::from_nsp(&p); // fallback option
+            ex = Extractor::from_nsp(&p, head); // fallback option
         }
         let Extractor { title_id, version } = ex?;
 
@@ -62,6 +66,10 @@ impl GameInfo {
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
 }
 
 impl Extractor {
@@ -98,9 +106,15 @@ impl Extractor {
         Ok(Extractor { title_id, version })
     }
 
-    fn from_nsp(path: &PathBuf) -> Result<Self, GameError> {
+    fn from_nsp(path: &Path, head: &[u8]) -> Result<Self, GameError> {
+        // head stops at MAX_HEAD_N - a header that runs past it has to come from the file
+        let nsp = match PFS0Header::from_bytes(head) {
+            Some(header) if header.len() > head.len() as u64 => Nsp::from_file(path)?,
+            _ => Nsp::from_head(head)?,
+        };
+
         // Right now, this is the fall back - all I can get is the titleid without decryption
-        let title_id = Nsp::from_file(path)?.title_id()?;
+        let title_id = nsp.title_id()?;
 
         let ex = Extractor {
             title_id: title_id,       // internally u64, sent as hex
@@ -1,21 +1,35 @@
 use std::{
-    fs, io,
+    fmt::Debug,
+    fs,
+    io::{self, Read},
     path::{Path, PathBuf},
     time::UNIX_EPOCH,
 };
 
 use thiserror::Error;
 
-use crate::game::{entry::GameEntry, info::GameInfo, nsp::NspParsingError};
+use crate::game::{
+    entry::GameEntry,
+    info::GameInfo,
+    nsp::{NspParsingError, PFS0Header},
+};
 
 pub mod entry;
 mod info;
 pub mod nsp;
 
+const HEAD_N: u64 = 0x8000; // installers probe the first few KiB when parsing the package
+const MAX_HEAD_N: u64 = 0x100000; // nsps with huge string tables just get their first 1mb kept
+
+/// Start of the file, read at scan time so header probes are answered from memory
+#[derive(PartialEq)]
+pub struct Head(Vec<u8>);
+
 #[derive(Debug, PartialEq)]
 pub struct Game {
     pub info: GameInfo,
     path: PathBuf,
+    head: Head,
 }
 
 #[derive(Error, Debug)]
@@ -39,6 +53,29 @@ fn get_mtime(path: &PathBuf) -> io::Result<f64> {
     Ok(duration.as_secs_f64())
 }
 
+impl Debug for Head {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.0.len())
+    }
+}
+
+impl Head {
+    /// HEAD_N bytes, or the whole pfs0 header if that's longer
+    fn read(path: &Path) -> io::Result<Self> {
+        let mut f = fs::File::open(path)?;
+        let mut buf = Vec::with_capacity(HEAD_N as usize);
+        (&mut f).take(HEAD_N).read_to_end(&mut buf)?;
+
+        if let Some(header) = PFS0Header::from_bytes(&buf)
+            && header.len() > buf.len() as u64
+        {
+            let rest = header.len().min(MAX_HEAD_N) - buf.len() as u64;
+            f.take(rest).read_to_end(&mut buf)?;
+        }
+        Ok(Self(buf))
+    }
+}
+
 impl TryFrom<&Game> for GameEntry {
     type Error = io::Error;
     fn try_from(value: &Game) -> Result<Self, Self::Error> {
@@ -51,9 +88,11 @@ impl TryFrom<&Game> for GameEntry {
 impl Game {
     pub fn try_new<P: AsRef<Path>>(path: P) -> Result<Self, GameError> {
         let path = path.as_ref(); // not always free
+        let head = Head::read(path)?; // the one read at scan time - nameless nsps are parsed from it too
         Ok(Self {
-            info: GameInfo::try_new(path)?, // game info also runs as_ref, but we make that free here
+            info: GameInfo::try_new(path, &head.0)?, // game info also runs as_ref, but we make that free here
             path: path.to_path_buf(),
+            head,
         })
     }
 
@@ -65,7 +104,54 @@ impl Game {
         &self.path
     }
 
+    /// same file, moved - nothing in it needs reading again
+    pub fn set_path(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
     pub fn game_info(&self) -> &GameInfo {
         &self.info
     }
+
+    /// [start, end) if it's all within the cached head - end is clamped to the file size
+    pub fn head(&self, start: u64, end: u64) -> Option<&[u8]> {
+        let end = end.min(self.size());
+        let start = start.min(end);
+        self.head.0.get(start as usize..end as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Library;
+
+    /// pfs0 with just a ticket - the string table padded out to `s_table_n`
+    fn nameless_nsp(lib: &Library, rel: &str, s_table_n: usize) -> PathBuf {
+        let mut names = b"0100000000010000000000000000000a.tik".to_vec();
+        names.resize(s_table_n, 0);
+
+        let mut nsp = b"PFS0".to_vec();
+        nsp.extend(1u32.to_le_bytes());
+        nsp.extend((s_table_n as u32).to_le_bytes());
+        nsp.extend([0u8; 4 + 24]); // padding, then a file entry at offset 0 of the string table
+        nsp.extend(names);
+
+        let path = lib.path().join(rel);
+        fs::write(&path, nsp).unwrap();
+        path
+    }
+
+    #[test]
+    fn nameless_nsps() {
+        // one that fits in the head, and one whose string table runs past MAX_HEAD_N
+        let lib = Library::new("nameless");
+        for (rel, n) in [
+            ("small.nsp", 0x100),
+            ("huge.nsp", MAX_HEAD_N as usize + 0x1000),
+        ] {
+            let game = Game::try_new(nameless_nsp(&lib, rel, n)).unwrap();
+            assert_eq!(game.game_info().title_id(), "0100000000010000", "{rel}");
+        }
+    }
 }
@@ -5,7 +5,7 @@ use std::{
     fs,
     io::{self, Read},
     mem,
-    path::PathBuf,
+    path::Path,
 };
 
 use bytemuck::{Pod, Zeroable};
@@ -56,8 +56,6 @@ pub struct Cnmt {
 }
 
 pub struct NspHeader {
-    pub pfs0_header: PFS0Header,
-    str_table: Vec<u8>,
     files: Files,
 }
 
@@ -80,6 +78,20 @@ pub enum NspParsingError {
     NoCnmt,
 }
 
+impl PFS0Header {
+    /// header + file entries + string table - everything before the first file's data
+    pub fn len(&self) -> u64 {
+        (mem::size_of::<PFS0Header>()
+            + self.n_files as usize * mem::size_of::<FileEntry>()
+            + self.s_table_size as usize) as u64
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let header: Self = bytemuck::pod_read_unaligned(buf.get(..mem::size_of::<PFS0Header>())?);
+        (&header.tag == HEADER).then_some(header)
+    }
+}
+
 impl Debug for File {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.name)
@@ -96,9 +108,18 @@ impl Files {
 }
 
 impl Nsp {
-    pub fn from_file(path: &PathBuf) -> Result<Self, NspParsingError> {
+    /// from the head read at scan time - no second trip to the disk
+    pub fn from_head(head: &[u8]) -> Result<Self, NspParsingError> {
+        Self::parse(head)
+    }
+
+    /// for headers too big to have been kept whole in the head
+    pub fn from_file(path: &Path) -> Result<Self, NspParsingError> {
+        Self::parse(fs::File::open(path)?)
+    }
+
+    fn parse(mut f: impl Read) -> Result<Self, NspParsingError> {
         // the following process is sequential - the f cursor is automatically advanced behind the scenes
-        let mut f = fs::OpenOptions::new().read(true).open(&path)?;
 
         // honestly; all this just to get filename + id
         // maybe picture sometime in future?
@@ -150,11 +171,7 @@ impl Nsp {
 
         let files = Files::from_vec(files);
 
-        let nsp_header = NspHeader {
-            pfs0_header,
-            str_table,
-            files,
-        };
+        let nsp_header = NspHeader { files };
 
         Ok(Self { nsp_header })
     }
@@ -0,0 +1,303 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use miniserde::{Deserialize, Serialize, json};
+
+use crate::device::{progress::Transfer, session::human_bytes};
+
+/*
+Install history - one json object per line, appended whenever a session completes or abandons a file
+Append-only so several frhop instances (one per console) can share a file, and a crash loses at most a line
+`frhop history` reads it back, filtered by console, title and date
+Requests that get refused (files outside the listing) go in an audit file next to it, same format
+*/
+
+const FILE_NAME: &str = "history.jsonl";
+const AUDIT_FILE_NAME: &str = "audit.jsonl";
+const DAY: u64 = 24 * 60 * 60;
+
+// None -> not recording (emulator, or history queries)
+// std mutex doubles as the append lock
+static HISTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub console: String, // serial, or bus-address if the device doesn't have one
+    pub title_id: Option<String>,
+    pub version: Option<String>,
+    pub path: String,
+    pub size: u64,
+    pub bytes: u64,   // distinct bytes of the file that went out
+    pub sent: u64,    // including re-reads
+    pub started: u64, // unix seconds
+    pub ended: u64,
+    pub outcome: String,
+}
+
+/// A request that was refused
+#[derive(Serialize, Debug)]
+pub struct Rejection {
+    pub time: u64, // unix seconds
+    pub console: String,
+    pub request: String, // whatever the console asked for, verbatim
+    pub reason: String,
+}
+
+/// What `frhop history` shows - every field that's set has to match
+#[derive(Default, Debug)]
+pub struct Query {
+    pub console: Option<String>,
+    pub title: Option<String>, // title id or part of the file name
+    pub since: Option<u64>,    // unix seconds, inclusive
+    pub until: Option<u64>,    // exclusive
+    pub outcome: Option<String>,
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// days <-> civil date, proleptic gregorian (http://howardhinnant.github.io/date_algorithms.html)
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// `2025-03-14` -> unix seconds at the start of that day (utc)
+pub fn parse_date(s: &str) -> Option<u64> {
+    let mut parts = s.trim().splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (Some(Some(y)), Some(Some(m)), Some(Some(d))) = (parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+
+    // rejects the 31st of february and friends
+    let days = days_from_civil(y, m, d);
+    (civil_from_days(days) == (y, m, d))
+        .then(|| u64::try_from(days).ok())
+        .flatten()
+        .map(|days| days * DAY)
+}
+
+/// end of the day `parse_date` starts
+pub fn parse_end_date(s: &str) -> Option<u64> {
+    parse_date(s).map(|t| t + DAY)
+}
+
+/// `2025-03-14 09:26` (utc)
+fn format_time(secs: u64) -> String {
+    let (y, m, d) = civil_from_days((secs / DAY) as i64);
+    let s = secs % DAY;
+    format!("{y:04}-{m:02}-{d:02} {:02}:{:02}", s / 3600, s / 60 % 60)
+}
+
+/// platform data dir, falls back to the working directory
+pub fn default_path() -> PathBuf {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".local/share")))
+    };
+    base.map_or(PathBuf::from(format!("frhop_{FILE_NAME}")), |b| {
+        b.join("frhop").join(FILE_NAME)
+    })
+}
+
+pub fn set_history(path: Option<PathBuf>) {
+    *HISTORY.lock().unwrap() = path;
+}
+
+fn append_line<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = json::to_string(value);
+    line.push('\n');
+    // one write - appends from other processes can't land in the middle of it
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// adds a line to the history file, if there is one
+pub fn append(record: &Record) -> io::Result<()> {
+    let history = HISTORY.lock().unwrap();
+    match history.as_ref() {
+        Some(path) => append_line(path, record),
+        None => Ok(()),
+    }
+}
+
+/// adds a line to the audit file - wherever the history file is
+pub fn audit(rejection: &Rejection) -> io::Result<()> {
+    let history = HISTORY.lock().unwrap();
+    match history.as_ref() {
+        Some(path) => append_line(&path.with_file_name(AUDIT_FILE_NAME), rejection),
+        None => Ok(()),
+    }
+}
+
+/// every record in the file, oldest first - lines that don't parse are skipped
+pub fn load(path: &Path) -> io::Result<Vec<Record>> {
+    let file = match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        res => res?,
+    };
+
+    let mut records = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match json::from_str::<Record>(line) {
+            Ok(r) => records.push(r),
+            Err(_) => println!("Warning; skipping malformed history line {}", i + 1),
+        }
+    }
+    Ok(records)
+}
+
+/// prints whatever matches, one record per line
+pub fn print(path: &Path, query: &Query) -> io::Result<()> {
+    let records = load(path)?;
+    let matching = records
+        .iter()
+        .filter(|r| query.matches(r))
+        .collect::<Vec<_>>();
+
+    for r in &matching {
+        println!("{r}");
+    }
+    println!(
+        "{} of {} records ({})",
+        matching.len(),
+        records.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+impl Record {
+    pub fn new(console: &str, t: &Transfer) -> Self {
+        Self {
+            console: console.to_string(),
+            title_id: t.title.as_ref().map(|t| t.id.clone()),
+            version: t.title.as_ref().map(|t| t.version.clone()),
+            path: t.path.to_string_lossy().to_string(),
+            size: t.size,
+            bytes: t.covered,
+            sent: t.sent,
+            started: unix_secs(t.started),
+            ended: unix_secs(t.ended),
+            outcome: t.outcome.to_string(),
+        }
+    }
+
+    fn file_name(&self) -> &str {
+        self.path.rsplit(['/', '\\']).next().unwrap_or(&self.path)
+    }
+}
+
+impl Rejection {
+    pub fn new(console: &str, request: &str, reason: &str) -> Self {
+        Self {
+            time: unix_secs(SystemTime::now()),
+            console: console.to_string(),
+            request: request.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let title = match (&self.title_id, &self.version) {
+            (Some(id), Some(v)) => format!("{id} v{v}"),
+            _ => "-".to_string(),
+        };
+        write!(
+            f,
+            "{}  {}  {title}  {} {}/{}  {}",
+            format_time(self.started),
+            self.console,
+            self.outcome,
+            human_bytes(self.bytes),
+            human_bytes(self.size),
+            self.file_name()
+        )
+    }
+}
+
+impl Query {
+    pub fn matches(&self, r: &Record) -> bool {
+        let title_matches = |t: &String| {
+            r.title_id
+                .as_ref()
+                .is_some_and(|id| id.eq_ignore_ascii_case(t))
+                || r.file_name().to_lowercase().contains(&t.to_lowercase())
+        };
+
+        self.console
+            .as_ref()
+            .is_none_or(|c| r.console.eq_ignore_ascii_case(c))
+            && self.title.as_ref().is_none_or(title_matches)
+            && self.since.is_none_or(|t| r.started >= t)
+            && self.until.is_none_or(|t| r.started < t)
+            && self
+                .outcome
+                .as_ref()
+                .is_none_or(|o| r.outcome.eq_ignore_ascii_case(o))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2025-03-14"), Some(1741910400));
+        assert_eq!(parse_end_date("1970-01-01"), Some(DAY));
+        assert_eq!(parse_date("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_date("2025-02-29"), None);
+        assert_eq!(parse_date("2025-13-01"), None);
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn formatted_time() {
+        assert_eq!(format_time(1741944360), "2025-03-14 09:26");
+    }
+}
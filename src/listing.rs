@@ -1,14 +1,39 @@
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use smol::io;
 use thiserror::Error;
 
-use crate::game::{Game, GameError};
+use crate::{
+    device::storage,
+    game::{Game, GameError},
+    scan::ScanRules,
+};
+
+/*
+Consoles never see host paths - every file gets a virtual name, `label/path relative to its root`
+A root's label defaults to its directory name (numbered if two roots share one), `label=path` to pick it
+Packages given directly are listed under their file name alone
+The watcher keeps it current through `refresh` and `rename`, `rescan` (SIGHUP) checks every root against the disk
+Files are stamped with their size + mtime when read, so only what's actually changed gets parsed again
+*/
+
+const DEFAULT_LABEL: &str = "Games"; // for roots without a usable name, e.g. `/`
+
+type Found = Vec<(String, PathBuf)>; // (virtual name, file) a scan turned up
 
 #[derive(Debug, Default)]
 pub struct Listing {
-    id_to_game: HashMap<String, Game>,   // game id -> game
-    file_to_id: HashMap<String, String>, // file name -> id
+    id_to_game: HashMap<String, Game>,        // game id -> game
+    file_to_id: HashMap<String, String>,      // virtual name -> id
+    stamps: HashMap<String, Stamp>,           // virtual name -> file it was read from
+    roots: HashMap<String, PathBuf>,          // label -> root dir
+    packages: Vec<(Option<String>, PathBuf)>, // packages given directly, with their label
+    rules: ScanRules,
 }
 
 #[derive(Error, Debug)]
@@ -17,20 +42,41 @@ pub enum ListingError {
     NotArchive,
     #[error("non utf-8 filename")]
     BadName,
+    #[error("label {0:?} is already used for {1:?}")]
+    DuplicateLabel(String, PathBuf),
+    #[error("{0:?} is already listed for {1:?}")]
+    DuplicateName(String, PathBuf),
     #[error("io error")]
     IoError(#[from] io::Error),
     #[error("game error")]
     GameError(#[from] GameError),
 }
 
+/// A listed file as it was when it was read
+#[derive(Debug, PartialEq)]
+struct Stamp {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// What a refresh or rescan did, by virtual name
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub updated: Vec<String>,
+    pub moved: Vec<(String, String)>,
+}
+
 pub enum ListingIndex<'a> {
     TitleId(&'a str),
-    FileName(&'a str),
 }
 
 impl Listing {
-    pub fn new() -> Self {
+    pub fn new(rules: ScanRules) -> Self {
         Self {
+            rules,
             ..Default::default()
         }
     }
@@ -43,19 +89,45 @@ impl Listing {
         &self.file_to_id
     }
 
-    fn add_file<P: AsRef<Path>>(&mut self, p: P) -> Result<(), ListingError> {
-        let p = p.as_ref();
+    /// `label/relative/path` under a root, or just the file name for a package given on its own
+    fn virtual_name(root: Option<(&str, &Path)>, p: &Path) -> Result<String, ListingError> {
+        let rel = match root {
+            Some((_, dir)) => p.strip_prefix(dir).map_err(|_| ListingError::BadName)?,
+            None => Path::new(p.file_name().ok_or(ListingError::BadName)?),
+        };
+        let mut parts = root.map(|(label, _)| vec![label]).unwrap_or_default();
+        for c in rel.components() {
+            parts.push(c.as_os_str().to_str().ok_or(ListingError::BadName)?);
+        }
+
+        let name = parts.join("/"); // sphaira splits on '/' whatever the host uses
+        // the list is newline separated
+        if name.contains('\n') {
+            return Err(ListingError::BadName);
+        }
+        Ok(name)
+    }
+
+    fn add_file(&mut self, name: String, p: &Path) -> Result<(), ListingError> {
         let ext = p
             .extension()
             .and_then(|e| e.to_str())
             .ok_or(ListingError::BadName)?;
 
-        let p_str = p.to_str().ok_or(ListingError::BadName)?;
-
-        if !matches!(ext, "nsp" | "xci" | "nsz" | "nsx") {
+        if !matches!(
+            ext.to_ascii_lowercase().as_str(),
+            "nsp" | "xci" | "nsz" | "nsx"
+        ) {
             return Err(ListingError::NotArchive);
         }
 
+        if let Some(stamp) = self.stamps.get(&name).filter(|s| s.path != p) {
+            return Err(ListingError::DuplicateName(name, stamp.path.clone()));
+        }
+
+        // stamped first - if it changes while it's being parsed, the next rescan catches it
+        let stamp = Stamp::read(p)?;
+        storage::forget(p); // whatever was cached is from before this stamp
         let game = Game::try_new(p)?;
         let id = game.game_info().title_id().to_string();
         if let Some(g) = self.id_to_game.get_mut(&id)
@@ -67,26 +139,38 @@ impl Listing {
             self.id_to_game
                 .insert(game.game_info().title_id().to_string(), game);
         }
-        self.file_to_id.insert(p_str.to_string(), id);
+        self.file_to_id.insert(name.clone(), id);
+        self.stamps.insert(name, stamp);
 
         Ok(())
     }
 
-    /// fill only fail for io errors
-    fn add_file_nonfatal<P: AsRef<Path>>(&mut self, p: P) -> io::Result<()> {
-        match self.add_file(&p) {
+    /// fill only fail for io errors - false if it wasn't added
+    fn add_file_nonfatal(&mut self, name: String, p: &Path) -> io::Result<bool> {
+        match self.add_file(name, p) {
             Err(ListingError::IoError(e)) => return Err(e),
-            Ok(_) | Err(ListingError::NotArchive | ListingError::BadName) => (), // ignore this error so user isn't bombarded with errors
-            Err(e) => {
-                let p = p.as_ref();
-                println!("Failed to add {p:?}: {e:?}")
-            }
+            Ok(_) => return Ok(true),
+            Err(ListingError::NotArchive | ListingError::BadName) => (), // ignore this error so user isn't bombarded with errors
+            Err(e) => println!("Failed to add {p:?}: {e:?}"),
         }
-        Ok(())
+        Ok(false)
     }
 
-    fn add_dir<P: AsRef<Path>>(&mut self, p: P) -> io::Result<()> {
-        for f in fs::read_dir(p)? {
+    /// walks `dir` (somewhere under `root`), following symlinks - `visited` holds the real path of every dir seen so
+    /// a link back up the tree isn't walked forever
+    fn walk(
+        &self,
+        (label, root): (&str, &Path),
+        dir: &Path,
+        depth: usize,
+        visited: &mut HashSet<PathBuf>,
+        found: &mut Found,
+    ) -> io::Result<()> {
+        if !visited.insert(dir.canonicalize()?) {
+            return Ok(());
+        }
+
+        for f in fs::read_dir(dir)? {
             // bit verbose but can be lax this way - bad files don't crash program
             let dir_entry = match f {
                 Err(ref e) => {
@@ -96,38 +180,658 @@ impl Listing {
                 Ok(f) => f,
             };
 
-            match dir_entry.file_type() {
+            // metadata rather than file_type - follows links
+            let path = dir_entry.path();
+            let meta = match fs::metadata(&path) {
                 Err(ref e) => {
-                    eprintln!("{e:?}");
+                    eprintln!("{path:?}: {e:?}");
                     continue;
                 }
-                Ok(f) if !f.is_file() => continue,
-                _ => (),
-            }
+                Ok(m) => m,
+            };
+            let Ok(name) = Self::virtual_name(Some((label, root)), &path) else {
+                continue;
+            };
 
-            self.add_file_nonfatal(dir_entry.path())?;
+            if meta.is_dir() {
+                if !self.rules.reaches(depth + 1) || self.rules.excludes(&format!("{name}/")) {
+                    continue;
+                }
+                // one bad subdir shouldn't lose the rest of the library
+                if let Err(e) = self.walk((label, root), &path, depth + 1, visited, found) {
+                    eprintln!("{path:?}: {e:?}");
+                }
+            } else if meta.is_file() && self.rules.wants(&name) {
+                found.push((name, path));
+            }
         }
         Ok(())
     }
 
+    /// dir name, numbered if another root already has it
+    fn default_label(&self, p: &Path) -> String {
+        let base = p
+            .canonicalize()
+            .ok()
+            .and_then(|p| p.file_name()?.to_str().map(str::to_string))
+            .unwrap_or(DEFAULT_LABEL.to_string());
+        (1..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{base} ({n})"),
+            })
+            .find(|l| !self.roots.contains_key(l))
+            .unwrap()
+    }
+
     /// Provide either file path OR dir path to scan at top-level
-    pub fn add<P: AsRef<Path>>(&mut self, p: P) -> Result<(), ListingError> {
-        let f = fs::metadata(&p)?;
+    /// listed under `label` - dirs default to their own name, packages to none
+    pub fn add<P: AsRef<Path>>(&mut self, label: Option<&str>, p: P) -> Result<(), ListingError> {
+        let p = p.as_ref();
+        let f = fs::metadata(p)?;
         if f.is_dir() {
-            self.add_dir(&p)?;
+            let label = match label {
+                Some(l) if l.is_empty() || l.contains(['/', '\\', '\n']) => {
+                    return Err(ListingError::BadName);
+                }
+                Some(l) => match self.roots.get(l) {
+                    Some(root) if root != p => {
+                        return Err(ListingError::DuplicateLabel(l.to_string(), root.clone()));
+                    }
+                    _ => l.to_string(),
+                },
+                None => self.default_label(p),
+            };
+            self.roots.insert(label, p.to_path_buf());
         } else {
-            self.add_file_nonfatal(&p)?;
+            self.packages
+                .push((label.map(str::to_string), p.to_path_buf()));
         }
+        self.refresh(p)?;
         Ok(())
     }
 
+    /// what the watcher needs to watch - (path, recursively)
+    /// packages are watched through their dir, a watch on the file itself dies with it when it's replaced
+    pub fn watched(&self) -> Vec<(PathBuf, bool)> {
+        let mut watched = self
+            .roots
+            .values()
+            .map(|dir| (dir.clone(), true))
+            .collect::<Vec<_>>();
+        for (_, p) in &self.packages {
+            let dir = p.parent().unwrap_or(Path::new("."));
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if !watched.iter().any(|(w, _)| w == dir) {
+                watched.push((dir.to_path_buf(), false));
+            }
+        }
+        watched
+    }
+
+    /// (label, dir) a path is named relative to, and whether it's under a root dir (rather than a package)
+    fn root_of(&self, p: &Path) -> Option<(Option<(String, PathBuf)>, bool)> {
+        if let Some((label, pkg)) = self.packages.iter().find(|(_, pkg)| pkg == p) {
+            // labelled packages go in a folder of their own
+            let root = label.clone().zip(pkg.parent().map(Path::to_path_buf));
+            return Some((root, false));
+        }
+        // nested roots - the innermost one names it
+        self.roots
+            .iter()
+            .filter(|(_, dir)| p.starts_with(dir))
+            .max_by_key(|(_, dir)| dir.components().count())
+            .map(|(label, dir)| (Some((label.clone(), dir.clone())), true))
+    }
+
+    /// whether the scan would've reached `p` - deep enough, and nothing on the way excluded
+    fn scanned(&self, (label, root): (&str, &Path), p: &Path, is_dir: bool) -> bool {
+        let Ok(rel) = p.strip_prefix(root) else {
+            return false;
+        };
+        let depth = rel
+            .components()
+            .count()
+            .saturating_sub(usize::from(!is_dir)); // dirs between the root and it - none if the root's now a file
+        let dirs = p
+            .ancestors()
+            .skip(usize::from(!is_dir))
+            .take_while(|a| *a != root);
+        self.rules.reaches(depth)
+            && dirs
+                .filter_map(|d| Self::virtual_name(Some((label, root)), d).ok())
+                .all(|name| !self.rules.excludes(&format!("{name}/")))
+    }
+
+    /// `p`'s virtual name, and every file at or under it a scan would list - None if it's not ours
+    fn find(&self, p: &Path) -> io::Result<Option<(String, Found)>> {
+        let Some((root, in_dir)) = self.root_of(p) else {
+            return Ok(None); // e.g. a neighbour of a package
+        };
+        let root = root.as_ref().map(|(l, r)| (l.as_str(), r.as_path()));
+        let Ok(name) = Self::virtual_name(root, p) else {
+            return Ok(None);
+        };
+
+        let mut found = Vec::new();
+        if let Ok(meta) = fs::metadata(p) {
+            match root.filter(|_| in_dir) {
+                // under a root dir - only what a scan from the top would've picked up
+                Some(dir_root) if !self.scanned(dir_root, p, meta.is_dir()) => (),
+                Some(dir_root) if meta.is_dir() => {
+                    let depth = p
+                        .strip_prefix(dir_root.1)
+                        .map_or(0, |r| r.components().count());
+                    // the root's already been seen, a link back to it is a loop
+                    let mut visited = HashSet::from([dir_root.1.canonicalize()?]);
+                    visited.remove(&p.canonicalize()?);
+                    self.walk(dir_root, p, depth, &mut visited, &mut found)?;
+                }
+                Some(_) if meta.is_file() && !self.rules.wants(&name) => (),
+                _ if meta.is_file() => found.push((name.clone(), p.to_path_buf())),
+                _ => (),
+            }
+        }
+        Ok(Some((name, found)))
+    }
+
+    /// drops names from both maps - a title goes with its last name, or is read again from another copy if the file
+    /// it was read from is gone
+    fn forget(&mut self, names: &[String]) {
+        let mut ids = HashSet::new();
+        let mut paths = HashSet::new();
+        for name in names {
+            ids.extend(self.file_to_id.remove(name));
+            paths.extend(self.stamps.remove(name).map(|s| s.path));
+        }
+        for p in &paths {
+            storage::forget(p);
+        }
+
+        for id in ids {
+            let other = self
+                .file_to_id
+                .iter()
+                .find(|(_, i)| **i == id)
+                .map(|(n, _)| self.stamps[n].path.clone());
+            let Some(other) = other else {
+                self.id_to_game.remove(&id);
+                continue;
+            };
+            if !self
+                .id_to_game
+                .get(&id)
+                .is_some_and(|g| paths.contains(g.path()))
+            {
+                continue;
+            }
+
+            match Game::try_new(&other) {
+                Ok(game) => {
+                    self.id_to_game.insert(id, game);
+                }
+                Err(e) => {
+                    println!("Failed to add {other:?}: {e:?}");
+                    self.id_to_game.remove(&id);
+                    let orphans = self
+                        .file_to_id
+                        .iter()
+                        .filter(|(_, i)| **i == id)
+                        .map(|(n, _)| n.clone())
+                        .collect::<Vec<_>>();
+                    for n in orphans {
+                        self.file_to_id.remove(&n);
+                        self.stamps.remove(&n);
+                    }
+                }
+            }
+        }
+    }
+
+    /// brings everything named `scope` (or under `scope/`) in line with what's on disk
+    fn reconcile(&mut self, scope: &str, found: Found) -> io::Result<Changes> {
+        let prefix = format!("{scope}/");
+        let found = found
+            .into_iter()
+            .map(|(name, p)| {
+                let stamp = Stamp::read(&p).ok();
+                (name, (p, stamp))
+            })
+            .collect::<HashMap<_, _>>();
+
+        // gone, or not what was read
+        let stale = self
+            .stamps
+            .iter()
+            .filter(|(n, _)| *n == scope || n.starts_with(&prefix))
+            .filter(|(n, s)| {
+                found
+                    .get(*n)
+                    .is_none_or(|(_, now)| now.as_ref() != Some(*s))
+            })
+            .map(|(n, _)| n.clone())
+            .collect::<Vec<_>>();
+        self.forget(&stale);
+
+        let mut changes = Changes::default();
+        for (name, (p, _)) in found {
+            if self.stamps.contains_key(&name) {
+                continue; // as it was
+            }
+            let was_listed = stale.contains(&name);
+            match (self.add_file_nonfatal(name.clone(), &p)?, was_listed) {
+                (true, true) => changes.updated.push(name),
+                (true, false) => changes.added.push(name),
+                (false, true) => changes.removed.push(name),
+                (false, false) => (),
+            }
+        }
+        for name in stale {
+            if !self.stamps.contains_key(&name) && !changes.removed.contains(&name) {
+                changes.removed.push(name);
+            }
+        }
+        changes.sort();
+        Ok(changes)
+    }
+
+    /// re-reads one path (file or dir, still there or not) - whatever the watcher saw change
+    pub fn refresh(&mut self, p: &Path) -> io::Result<Changes> {
+        match self.find(p)? {
+            Some((scope, found)) => self.reconcile(&scope, found),
+            None => Ok(Changes::default()),
+        }
+    }
+
+    /// stops listing `p`, and anything under it if it's a dir
+    pub fn remove(&mut self, p: &Path) -> Changes {
+        let Ok(Some((scope, _))) = self.find(p) else {
+            return Changes::default();
+        };
+        // nothing found -> can't fail
+        self.reconcile(&scope, Vec::new()).unwrap_or_default()
+    }
+
+    /// `from` is now at `to` - files that kept their name are carried over as they are, rather than parsed again
+    /// (a renamed file isn't, its name may be where the title id came from)
+    pub fn rename(&mut self, from: &Path, to: &Path) -> io::Result<Changes> {
+        let mut moved = Vec::new();
+        if let (Some((from_scope, _)), Some((to_scope, found))) = (self.find(from)?, self.find(to)?)
+        {
+            let prefix = format!("{from_scope}/");
+            let landed = found
+                .iter()
+                .map(|(n, p)| (p.clone(), n.clone()))
+                .collect::<HashMap<_, _>>();
+            let names = self
+                .stamps
+                .keys()
+                .filter(|n| **n == from_scope || n.starts_with(&prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for old in names {
+                let old_path = self.stamps[&old].path.clone();
+                let new_path = match old_path.strip_prefix(from) {
+                    Ok(rel) if rel.as_os_str().is_empty() => to.to_path_buf(),
+                    Ok(rel) => to.join(rel),
+                    Err(_) => continue,
+                };
+                // only where a scan would've put it
+                let Some(new) = landed.get(&new_path) else {
+                    continue;
+                };
+                if new_path.file_name() != old_path.file_name() || self.stamps.contains_key(new) {
+                    continue;
+                }
+
+                let (Some(id), Some(mut stamp)) =
+                    (self.file_to_id.remove(&old), self.stamps.remove(&old))
+                else {
+                    continue;
+                };
+                if let Some(g) = self.id_to_game.get_mut(&id)
+                    && g.path() == &old_path
+                {
+                    g.set_path(new_path.clone());
+                }
+                storage::forget(&old_path);
+                stamp.path = new_path;
+                self.file_to_id.insert(new.clone(), id);
+                self.stamps.insert(new.clone(), stamp);
+                moved.push((old, new.clone()));
+            }
+
+            // what's left at either end is read as usual
+            let mut changes = self.refresh(from)?;
+            changes.extend(self.reconcile(&to_scope, found)?);
+            changes.moved = moved;
+            changes.sort();
+            return Ok(changes);
+        }
+
+        let mut changes = self.refresh(from)?;
+        changes.extend(self.refresh(to)?);
+        Ok(changes)
+    }
+
+    /// every root checked against the disk - what's changed since it was read is read again
+    pub fn rescan(&mut self) -> Changes {
+        let mut paths = self.roots.values().cloned().collect::<Vec<_>>();
+        paths.extend(self.packages.iter().map(|(_, p)| p.clone()));
+
+        let mut changes = Changes::default();
+        for p in paths {
+            // one unreadable root shouldn't hold the others up
+            match self.refresh(&p) {
+                Ok(c) => changes.extend(c),
+                Err(e) => eprintln!("{p:?}: {e:?}"),
+            }
+        }
+        changes.sort();
+        changes
+    }
+
+    /// the file a name was listed for, its size, and its title - which may have been read from another copy, so
+    /// anything about the file itself comes from here rather than the game
+    pub fn resolve(&self, name: &str) -> Option<(&Path, u64, &Game)> {
+        let stamp = self.stamps.get(name)?;
+        let game = self.id_to_game.get(self.file_to_id.get(name)?)?;
+        Some((&stamp.path, stamp.len, game))
+    }
+
     pub fn get_game(&self, index: ListingIndex) -> Option<&Game> {
         match index {
-            ListingIndex::FileName(f_name) => self
-                .file_map()
-                .get(f_name)
-                .and_then(|id| self.id_map().get(id)),
             ListingIndex::TitleId(t_id) => self.id_map().get(t_id),
         }
     }
 }
+
+/// `label=path` -> (label, path) - unless the whole thing's an existing path
+pub fn split_label(spec: &str) -> (Option<&str>, &str) {
+    match spec.split_once('=') {
+        Some((label, path)) if !Path::new(spec).exists() => (Some(label.trim()), path.trim()),
+        _ => (None, spec),
+    }
+}
+
+impl Stamp {
+    fn read(path: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+impl Changes {
+    fn extend(&mut self, other: Changes) {
+        self.added.extend(other.added);
+        self.removed.extend(other.removed);
+        self.updated.extend(other.updated);
+        self.moved.extend(other.moved);
+    }
+
+    fn sort(&mut self) {
+        self.added.sort();
+        self.removed.sort();
+        self.updated.sort();
+        self.moved.sort();
+    }
+
+    /// counts only
+    pub fn summary(&self) -> String {
+        format!(
+            "{} added, {} removed, {} updated, {} moved",
+            self.added.len(),
+            self.removed.len(),
+            self.updated.len(),
+            self.moved.len()
+        )
+    }
+}
+
+/// one line per file
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for name in &self.added {
+            writeln!(f, "Added; {name}")?;
+        }
+        for name in &self.removed {
+            writeln!(f, "Removed; {name}")?;
+        }
+        for name in &self.updated {
+            writeln!(f, "Updated; {name}")?;
+        }
+        for (from, to) in &self.moved {
+            writeln!(f, "Moved; {from} -> {to}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Library;
+
+    const ZELDA: &str = "Zelda [0100000000010000][v0].nsp";
+    const MARIO: &str = "Mario [0100000000020000][v0].nsp";
+
+    fn listing(lib: &Library) -> Listing {
+        let mut listing = Listing::new(ScanRules::default());
+        listing.add(Some("Lib"), lib.path()).unwrap();
+        listing
+    }
+
+    /// every name has a stamp and a title, every title has a name, and it was read from one of them
+    fn assert_consistent(listing: &Listing) {
+        let mut names = listing.file_to_id.keys().collect::<Vec<_>>();
+        let mut stamped = listing.stamps.keys().collect::<Vec<_>>();
+        names.sort();
+        stamped.sort();
+        assert_eq!(names, stamped);
+
+        for (id, game) in &listing.id_to_game {
+            assert!(
+                listing
+                    .file_to_id
+                    .iter()
+                    .any(|(n, i)| i == id && listing.stamps[n].path == *game.path()),
+                "{id} isn't read from any of its names"
+            );
+        }
+        for id in listing.file_to_id.values() {
+            assert!(listing.id_to_game.contains_key(id), "{id} has no title");
+        }
+    }
+
+    fn names(listing: &Listing) -> Vec<&str> {
+        let mut names = listing
+            .file_to_id
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn lists_under_label() {
+        let lib = Library::new("label");
+        lib.write(ZELDA, 10);
+        lib.write(&format!("sub/{MARIO}"), 20);
+        lib.write("readme.txt", 5);
+
+        let listing = listing(&lib);
+        assert_eq!(
+            names(&listing),
+            [format!("Lib/{ZELDA}"), format!("Lib/sub/{MARIO}")]
+        );
+        assert_consistent(&listing);
+    }
+
+    #[test]
+    fn refresh_reads_what_changed() {
+        let lib = Library::new("refresh");
+        let mut listing = listing(&lib);
+        let zelda = lib.write(ZELDA, 10);
+        listing.refresh(&zelda).unwrap();
+        assert_eq!(names(&listing), [format!("Lib/{ZELDA}")]);
+
+        lib.write(ZELDA, 20);
+        listing.refresh(&zelda).unwrap();
+        assert_eq!(listing.id_map()["0100000000010000"].size(), 20);
+
+        fs::remove_file(&zelda).unwrap();
+        listing.refresh(&zelda).unwrap();
+        assert!(names(&listing).is_empty());
+    }
+
+    #[test]
+    fn remove_forgets_file_and_dir() {
+        let lib = Library::new("remove");
+        let zelda = lib.write(ZELDA, 10);
+        lib.write(&format!("sub/{MARIO}"), 20);
+        let mut listing = listing(&lib);
+
+        fs::remove_file(&zelda).unwrap();
+        let changes = listing.remove(&zelda);
+        assert_eq!(changes.removed, [format!("Lib/{ZELDA}")]);
+        assert!(!listing.id_to_game.contains_key("0100000000010000"));
+        assert_consistent(&listing);
+
+        fs::remove_dir_all(lib.path().join("sub")).unwrap();
+        let changes = listing.remove(&lib.path().join("sub"));
+        assert_eq!(changes.removed, [format!("Lib/sub/{MARIO}")]);
+        assert!(listing.id_to_game.is_empty() && listing.stamps.is_empty());
+    }
+
+    #[test]
+    fn rename_carries_files_over() {
+        let lib = Library::new("rename");
+        lib.write(&format!("old/{ZELDA}"), 10);
+        let mut listing = listing(&lib);
+
+        let (from, to) = (lib.path().join("old"), lib.path().join("new"));
+        fs::rename(&from, &to).unwrap();
+        let changes = listing.rename(&from, &to).unwrap();
+        assert_eq!(
+            changes.moved,
+            [(format!("Lib/old/{ZELDA}"), format!("Lib/new/{ZELDA}"))]
+        );
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        assert_eq!(
+            listing
+                .get_game(ListingIndex::TitleId("0100000000010000"))
+                .unwrap()
+                .path(),
+            &to.join(ZELDA)
+        );
+        assert_consistent(&listing);
+    }
+
+    #[test]
+    fn renamed_file_is_read_again() {
+        let lib = Library::new("rename-file");
+        let from = lib.write(ZELDA, 10);
+        let mut listing = listing(&lib);
+
+        // the id's in the name - a new one can be a new title
+        let to = lib.path().join(MARIO);
+        fs::rename(&from, &to).unwrap();
+        let changes = listing.rename(&from, &to).unwrap();
+        assert_eq!(changes.removed, [format!("Lib/{ZELDA}")]);
+        assert_eq!(changes.added, [format!("Lib/{MARIO}")]);
+        assert!(changes.moved.is_empty());
+        assert_eq!(
+            listing.id_to_game.keys().collect::<Vec<_>>(),
+            ["0100000000020000"]
+        );
+        assert_consistent(&listing);
+    }
+
+    #[test]
+    fn rescan_finds_what_changed() {
+        let lib = Library::new("rescan");
+        let zelda = lib.write(ZELDA, 10);
+        let mario = lib.write(MARIO, 20);
+        let mut listing = listing(&lib);
+
+        fs::remove_file(&mario).unwrap();
+        lib.write(ZELDA, 30);
+        lib.write("Small [0100000000030000][v0].nsp", 40);
+
+        let changes = listing.rescan();
+        assert_eq!(changes.added, ["Lib/Small [0100000000030000][v0].nsp"]);
+        assert_eq!(changes.removed, [format!("Lib/{MARIO}")]);
+        assert_eq!(changes.updated, [format!("Lib/{ZELDA}")]);
+        assert_eq!(listing.stamps[&format!("Lib/{ZELDA}")].len, 30);
+        assert_eq!(
+            listing
+                .get_game(ListingIndex::TitleId("0100000000010000"))
+                .unwrap()
+                .path(),
+            &zelda
+        );
+        assert_consistent(&listing);
+
+        // nothing's changed since
+        let changes = listing.rescan();
+        assert_eq!(changes.summary(), "0 added, 0 removed, 0 updated, 0 moved");
+    }
+
+    #[test]
+    fn duplicate_titles_keep_their_own_files() {
+        let lib = Library::new("duplicate");
+        let base = lib.write(&format!("Base/{ZELDA}"), 10);
+        let backup = lib.write(&format!("Backup/{ZELDA}"), 20);
+        let mut listing = listing(&lib);
+        assert_eq!(listing.id_to_game.len(), 1);
+        assert_consistent(&listing);
+
+        // each name is served from its own file, whichever one the title was read from
+        let (path, len, _) = listing.resolve(&format!("Lib/Base/{ZELDA}")).unwrap();
+        assert_eq!((path, len), (base.as_path(), 10));
+        let (path, len, _) = listing.resolve(&format!("Lib/Backup/{ZELDA}")).unwrap();
+        assert_eq!((path, len), (backup.as_path(), 20));
+
+        // the title's read again from whichever copy's left
+        let read_from = listing.id_to_game["0100000000010000"].path().clone();
+        let other = if read_from == base { &backup } else { &base };
+        fs::remove_file(&read_from).unwrap();
+        listing.remove(&read_from);
+        assert_eq!(listing.id_to_game["0100000000010000"].path(), other);
+        assert_eq!(
+            listing.id_to_game["0100000000010000"].size(),
+            fs::metadata(other).unwrap().len()
+        );
+        assert_consistent(&listing);
+
+        fs::remove_file(other).unwrap();
+        listing.remove(other);
+        assert!(listing.id_to_game.is_empty() && listing.file_to_id.is_empty());
+    }
+
+    #[test]
+    fn root_replaced_by_file() {
+        let lib = Library::new("root-file");
+        let root = lib.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join(ZELDA), [0u8; 10]).unwrap();
+        let mut listing = Listing::new(ScanRules::default());
+        listing.add(Some("Root"), &root).unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::write(&root, [0u8; 10]).unwrap();
+        let changes = listing.refresh(&root).unwrap();
+        assert_eq!(changes.removed, [format!("Root/{ZELDA}")]);
+        assert!(listing.id_to_game.is_empty());
+    }
+}
@@ -8,13 +8,26 @@ use smol::{
 };
 
 use crate::{
-    device::{UsbClient, interface::SwitchInterface},
-    listing::Listing,
+    client::emulate,
+    config::{Config, USAGE},
+    device::{
+        UsbClient, filter::set_filter, manager::DeviceManager, shutdown, throttle::set_limits,
+        writer::set_read_ahead,
+    },
+    history::set_history,
+    listing::{Listing, split_label},
 };
 
+mod client;
+mod config;
 mod device;
+#[cfg(test)]
+mod fixture;
 mod game;
+mod history;
 mod listing;
+mod scan;
+mod watcher;
 
 const N_THREADS: usize = 4; // turn this up to increase thread count, but come on >4 is overkill for this
 
@@ -36,11 +49,17 @@ fn main() {
 
     // it's not like tinfoil supports refreshing when its reconnected
     // all this is because on windows device ownership isn't released if we just halt the program
+    // first ctrl-c lets the manager wind sessions down, second one stops everything where it is
+    let force = signal.clone();
     ctrlc::set_handler(move || {
-        println!("shutting down connections");
+        if shutdown::request() {
+            println!("shutting down connections (ctrl-c again to force)");
+            return;
+        }
+        println!("forcing shutdown");
         // close down executor threads (including main)
         for _ in 0..N_THREADS + 1 {
-            let _ = signal.send_blocking(()); // need to do this so the Device object is dropped (release ownership)
+            let _ = force.send_blocking(()); // need to do this so the Device object is dropped (release ownership)
         }
     })
     .expect("ctrl-c override failed");
@@ -49,6 +68,11 @@ fn main() {
     let ex_clone = ex.clone();
     future::block_on(ex_clone.run(race(shutdown(), async_main(ex))));
 
+    // manager's done (or forced) - the other threads still need telling
+    for _ in 0..N_THREADS {
+        let _ = signal.send_blocking(());
+    }
+
     // if we're here, cancel signal sent and tasks finished
     for thread in threads {
         thread.join().unwrap()
@@ -57,60 +81,117 @@ fn main() {
 }
 
 async fn async_main(executor: Arc<Executor<'_>>) {
-    let mut listing = Listing::new();
-
-    let mut client = Default::default();
-
-    let mut args = std::env::args().skip(1);
-    if let Some(d) = args.next() {
-        if let Some(("", t)) = d.split_once("-")
-            && let Ok(c) = UsbClient::try_from(t)
-        {
-            client = c;
-        } else {
-            listing.add(d).unwrap();
+    let Config {
+        emulate: emulating,
+        history,
+        client,
+        filter,
+        read_ahead,
+        limit,
+        device_limit,
+        history_file,
+        query,
+        scan,
+        paths,
+    } = match Config::from_args(std::env::args().skip(1)) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{e}\nUsage: {USAGE}");
+            exit(-1)
         }
-    } else {
-        println!("Specify a [list of] directories or packages to serve");
+    };
+    set_filter(filter);
+    set_limits(limit, device_limit);
+    if let Some(n) = read_ahead {
+        set_read_ahead(n);
+    }
+
+    let history_file = history_file.unwrap_or_else(history::default_path);
+    if history {
+        if let Err(e) = history::print(&history_file, &query) {
+            println!("Failed to read install history: {e}");
+            exit(-1)
+        }
+        exit(0)
+    }
+    if !emulating {
+        set_history(Some(history_file)); // emulated sessions aren't installs
+    }
+
+    if paths.is_empty() {
+        println!("Specify a [list of] directories or packages to serve\nUsage: {USAGE}");
         exit(-1)
     }
 
-    for d in args {
-        listing.add(&d).unwrap();
+    let mut listing = Listing::new(scan);
+    for d in paths {
+        let (label, path) = split_label(&d);
+        if let Err(e) = listing.add(label, path) {
+            println!("Failed to add {d:?}: {e}");
+            exit(-1)
+        }
     }
 
     if listing.id_map().is_empty() {
         println!(
             "Either all files specified are invalid archives or none of the directories contain switch archives!"
         );
-        exit(-1)
+        // nothing to emulate against - otherwise they can still be copied in
+        if emulating {
+            exit(-1)
+        }
+        println!("Watching for new ones");
     }
 
     println!("{} nsps found", listing.id_map().len());
 
     let listing = Arc::new(RwLock::new(listing));
 
-    println!("Waiting for {}", client);
-    loop {
-        let device = loop {
-            match SwitchInterface::wait_new(listing.clone()).await {
-                Ok(device) => break device,
-                Err(e) => println!("Error connecting: {e:?}"),
+    if emulating {
+        // no flag -> check both
+        let clients = client.map_or(vec![UsbClient::Tinfoil, UsbClient::Sphaira], |c| vec![c]);
+        let mut failed = false;
+        for c in clients {
+            match emulate(c, listing.clone()).await {
+                Ok(r) => println!(
+                    "{c}: verified {} ranges ({} bytes) across {} titles",
+                    r.ranges, r.bytes, r.titles
+                ),
+                Err(e) => {
+                    println!("{c}: emulation failed; {e}");
+                    failed = true;
+                }
             }
-        };
-        println!("Connected!");
+        }
+        exit(if failed { -1 } else { 0 })
+    }
 
+    let watched = listing.clone();
+    executor
+        .spawn(async move {
+            if let Err(e) = watcher::watch(watched).await {
+                println!("Failed to watch the library, changes need a restart; {e}");
+            }
+        })
+        .detach();
+    #[cfg(unix)]
+    {
+        let rescanned = listing.clone();
         executor
             .spawn(async move {
-                if let Err(e) = client.start_interface(device).await {
-                    eprintln!("{e:?} (switch disconnected?)");
+                if let Err(e) = watcher::rescan_on_hangup(rescanned).await {
+                    println!("Failed to listen for SIGHUP, rescans need a restart; {e}");
                 }
             })
-            .detach(); // don't need the task
+            .detach();
     }
 
-    /*
-    if listing is invariant - no need to serialise on every search request
-    implement a file watcher?
-     */
+    match client {
+        Some(c) => println!("Waiting for {c}"),
+        None => println!("Waiting for a switch (Tinfoil or Sphaira, detected per device)"),
+    }
+    if let Err(e) = DeviceManager::new(listing, client).run(&executor).await {
+        println!("Failed to watch for devices: {e:?}");
+        exit(-1)
+    }
 }
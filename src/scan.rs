@@ -0,0 +1,90 @@
+/*
+Which files a directory scan picks up
+Patterns match against the name the console sees (`label/sub/dir/file.nsp`), so they read the same on any host
+`*` is any run of characters (slashes included), `?` is any one, case doesn't matter
+e.g. excluding `_trash` with a `*` either side (and its slashes) skips every `_trash` dir, however deep
+*/
+
+#[derive(Debug, Default)]
+pub struct ScanRules {
+    pub max_depth: Option<usize>, // subdirs deep, 0 -> top level only, None -> no limit
+    pub include: Vec<String>,     // empty -> everything
+    pub exclude: Vec<String>,     // wins over include
+}
+
+/// `*` and `?` wildcards, ascii case-insensitive
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n) = (pattern.as_bytes(), name.as_bytes());
+    let (mut pi, mut ni) = (0, 0);
+    let mut star = None; // (pattern index after the last *, name index it was tried at)
+
+    while ni < n.len() {
+        match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi + 1, ni));
+                pi += 1;
+            }
+            Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&n[ni]) => {
+                pi += 1;
+                ni += 1;
+            }
+            // mismatch - let the last * swallow one more character
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    pi = sp;
+                    ni = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+impl ScanRules {
+    /// a file's worth listing
+    pub fn wants(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, name)))
+            && !self.excludes(name)
+    }
+
+    /// dirs are checked as `name/`, so `*/_trash/*` prunes the whole thing without reading it
+    pub fn excludes(&self, name: &str) -> bool {
+        self.exclude.iter().any(|p| glob_match(p, name))
+    }
+
+    /// dirs this many levels below a root get scanned
+    pub fn reaches(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*.nsp", "Lib/sub/Zelda.NSP"));
+        assert!(glob_match("*/_trash/*", "Lib/a/_trash/b/"));
+        assert!(glob_match("Lib/?elda*", "Lib/Zelda [0100].nsp"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(!glob_match("*.nsp", "Zelda.nsz"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("Lib/*", "Other/Zelda.nsp"));
+    }
+
+    #[test]
+    fn exclude_wins() {
+        let rules = ScanRules {
+            include: vec!["*.nsp".into()],
+            exclude: vec!["*demo*".into()],
+            ..Default::default()
+        };
+        assert!(rules.wants("Lib/Zelda.nsp"));
+        assert!(!rules.wants("Lib/Zelda demo.nsp"));
+        assert!(!rules.wants("Lib/Zelda.xci"));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use notify::{
+    EventKind, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
+};
+use smol::{Timer, channel::unbounded, future, io, lock::RwLock, unblock};
+
+use crate::listing::{Changes, Listing};
+
+/*
+Keeps the listing in step with the disk - every root's watched, and paths that change are read again once settled
+A file being copied in fires events the whole way through, so it's left alone until it's been quiet for SETTLE and
+its size and mtime haven't moved since - a half copied archive would fail to parse, or get listed short
+Renames seen from both ends are carried over straight away - nothing's being written, and nothing needs parsing
+Sessions take the listing as they go, so the next connection (or Sphaira list) sees the update
+SIGHUP rescans every root, for whatever the watcher can't see (e.g. changes made on the other end of a network mount)
+*/
+
+const SETTLE: Duration = Duration::from_secs(2);
+const TICK: Duration = Duration::from_millis(500); // how often settling paths are checked
+
+type Stamp = Option<(u64, SystemTime)>; // None -> gone
+
+struct Pending {
+    last_event: Instant,
+    stamp: Stamp,
+}
+
+fn stamp(p: &Path) -> Stamp {
+    let meta = fs::metadata(p).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+fn report(p: &Path, changes: io::Result<Changes>) {
+    match changes {
+        Ok(changes) => print!("{changes}"),
+        Err(e) => println!("Failed to update {p:?}: {e:?}"),
+    }
+}
+
+/// runs until the watcher dies - changes are applied as they settle
+pub async fn watch(listing: Arc<RwLock<Listing>>) -> notify::Result<()> {
+    let (tx, rx) = unbounded();
+    // notify calls this from a thread of its own
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.try_send(res);
+    })?;
+    for (path, recursive) in listing.read().await.watched() {
+        let mode = match recursive {
+            true => RecursiveMode::Recursive,
+            false => RecursiveMode::NonRecursive,
+        };
+        watcher.watch(&path, mode)?;
+    }
+
+    let mut pending = HashMap::<PathBuf, Pending>::new();
+    loop {
+        // nothing to settle -> nothing to check
+        let tick = async {
+            match pending.is_empty() {
+                true => future::pending().await,
+                false => Timer::after(TICK).await,
+            };
+            None
+        };
+        match future::or(async { Some(rx.recv().await) }, tick).await {
+            Some(Ok(Ok(event))) => {
+                // sessions reading games show up as accesses
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) =
+                    (event.kind, event.paths.as_slice())
+                {
+                    let (from, to) = (from.clone(), to.clone());
+                    let mut listing = listing.write_arc().await;
+                    unblock(move || report(&to, listing.rename(&from, &to))).await;
+                    continue;
+                }
+                for p in event.paths {
+                    let stamp = stamp(&p);
+                    let last_event = Instant::now();
+                    pending.insert(p, Pending { last_event, stamp });
+                }
+            }
+            Some(Ok(Err(e))) => println!("Library watcher error; {e}"),
+            Some(Err(_)) => return Ok(()), // watcher's gone
+            None => (),
+        }
+
+        let mut settled = Vec::new();
+        for (p, entry) in &mut pending {
+            if entry.last_event.elapsed() < SETTLE {
+                continue;
+            }
+            let now = stamp(p);
+            if now == entry.stamp {
+                settled.push(p.clone());
+            } else {
+                // still being written, even if it's not telling us
+                *entry = Pending {
+                    last_event: Instant::now(),
+                    stamp: now,
+                };
+            }
+        }
+        if settled.is_empty() {
+            continue;
+        }
+        for p in &settled {
+            pending.remove(p);
+        }
+        // re-reading a dir covers everything in it
+        let dirs = settled.clone();
+        settled.retain(|p| !dirs.iter().any(|d| d != p && p.starts_with(d)));
+
+        // parsing's blocking io - the lock's held throughout so no session sees it half done
+        let mut listing = listing.write_arc().await;
+        unblock(move || {
+            for p in settled {
+                let changes = match p.exists() {
+                    true => listing.refresh(&p),
+                    false => Ok(listing.remove(&p)),
+                };
+                report(&p, changes);
+            }
+        })
+        .await;
+    }
+}
+
+/// SIGHUP -> rescan, runs for as long as frhop does
+#[cfg(unix)]
+pub async fn rescan_on_hangup(listing: Arc<RwLock<Listing>>) -> io::Result<()> {
+    use async_signal::{Signal, Signals};
+    use smol::stream::StreamExt;
+
+    let mut signals = Signals::new([Signal::Hup])?;
+    while signals.next().await.is_some() {
+        println!("Rescanning library");
+        let mut listing = listing.write_arc().await;
+        let changes = unblock(move || listing.rescan()).await;
+        print!("{changes}");
+        println!("Rescanned; {}", changes.summary());
+    }
+    Ok(())
+}